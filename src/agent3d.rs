@@ -1,58 +1,261 @@
-use bevy::{prelude::*, utils::EntityHashMap};
+use bevy::{
+    ecs::system::EntityCommands,
+    prelude::*,
+    utils::{EntityHashMap, HashMap, Parallel},
+};
 use fastrand::Rng;
+use serde::{Deserialize, Serialize};
 use vleue_navigator::prelude::*;
 
-use crate::{Materials, MAP_SIZE};
-
-const MOVEMENT_SPEED: f32 = 8.0;
+use crate::{
+    archetype::Archetypes,
+    avoidance::{avoid_collisions, Avoidance, Velocity},
+    clearance::{
+        snap_to_navmesh, ClassNavMesh, FoundPath, PathNavMeshes, SearchNavMeshes, SizeClass,
+    },
+    clock::{InterpolatedTranslation, TimeOfDay},
+    flow_field::{FlowFieldKey, FlowFields},
+    group::{spawn_group, Follower, GroupSettings},
+    pace::Pace,
+    path_cache::{invalidate_path_cache, visible, PathCache},
+    path_requests::{
+        apply_path_results, dispatch_path_requests, PathRequest, PathRequestBudget, PathRequests,
+        WaitingForPath,
+    },
+    queue::{Queueing, StallQueue},
+    scenario::ObstacleShape,
+    shopping::Shopper,
+    spatial::{update_spatial_grid, SpatialGrid},
+    stall::{Stall, VisitingStall},
+    steering::Steering,
+    EntityRng, MapSize, Materials,
+};
 
 pub struct MovementPlugin;
 
 impl Plugin for MovementPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(
-            Update,
-            (new_paths, give_target_to_navigator, move_navigator).chain(),
-        );
+        app.init_resource::<SimulationStats>()
+            .init_resource::<ChangedAreas>()
+            .init_resource::<StuckNavigators>()
+            .init_resource::<SpatialGrid>()
+            .init_resource::<PathRequests>()
+            .init_resource::<PathRequestBudget>()
+            .init_resource::<PathCache>()
+            .add_systems(Update, new_paths)
+            // Navigators move by a fixed amount of time each tick, so that runs are reproducible.
+            .add_systems(
+                FixedUpdate,
+                (
+                    track_obstacle_changes,
+                    refresh_path,
+                    recover_off_mesh,
+                    count_stuck_navigators,
+                    invalidate_path_cache,
+                    apply_path_results,
+                    give_target_to_navigator,
+                    dispatch_path_requests,
+                    update_spatial_grid,
+                    follow_path,
+                    avoid_collisions,
+                    move_navigator,
+                )
+                    .chain(),
+            );
     }
 }
 
-#[derive(Component)]
+#[derive(Component, Clone, Serialize, Deserialize)]
 pub struct Navigator {
-    speed: f32,
+    pub speed: f32,
     // color: Color,
 }
 
-#[derive(Component)]
+#[derive(Component, Clone, Serialize, Deserialize)]
 pub struct Path {
     current: Vec3,
     next: Vec<Vec3>,
+    /// The field the next waypoints are taken from until the target is in sight.
+    #[serde(default)]
+    flow_field: Option<FlowFieldKey>,
 }
 
+impl Path {
+    /// A path following `waypoints` in order, `None` if there are none.
+    pub fn from_waypoints(waypoints: &[Vec3]) -> Option<Self> {
+        let (first, remaining) = waypoints.split_first()?;
+        let mut next = remaining.to_vec();
+        next.reverse();
+        Some(Path {
+            current: *first,
+            next,
+            flow_field: None,
+        })
+    }
+
+    /// A path following `found`, and its flow field if it has one.
+    pub fn from_found(found: &FoundPath) -> Option<Self> {
+        let mut path = Self::from_waypoints(&found.path)?;
+        path.flow_field = found.flow_field;
+        Some(path)
+    }
+
+    /// The last waypoint of the path.
+    pub fn target(&self) -> Vec3 {
+        self.next.first().copied().unwrap_or(self.current)
+    }
+
+    /// Whether the rest of the path, walked from `position`, goes through any of `areas`.
+    fn crosses(&self, position: Vec3, areas: &[Rect]) -> bool {
+        let mut from = position.xz();
+        for to in std::iter::once(self.current).chain(self.next.iter().rev().copied()) {
+            let to = to.xz();
+            if areas
+                .iter()
+                .any(|area| segment_intersects_rect(from, to, *area))
+            {
+                return true;
+            }
+            from = to;
+        }
+        false
+    }
+}
+
+/// Whether the segment from `a` to `b` touches `rect`.
+fn segment_intersects_rect(a: Vec2, b: Vec2, rect: Rect) -> bool {
+    let direction = b - a;
+    let mut t_min = 0.0_f32;
+    let mut t_max = 1.0_f32;
+    for axis in 0..2 {
+        if direction[axis].abs() < f32::EPSILON {
+            if a[axis] < rect.min[axis] || a[axis] > rect.max[axis] {
+                return false;
+            }
+        } else {
+            let t1 = (rect.min[axis] - a[axis]) / direction[axis];
+            let t2 = (rect.max[axis] - a[axis]) / direction[axis];
+            t_min = t_min.max(t1.min(t2));
+            t_max = t_max.min(t1.max(t2));
+            if t_min > t_max {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Running totals of what the navigators have been doing, used for the headless summary.
+#[derive(Resource, Default)]
+pub struct SimulationStats {
+    pub paths_found: u32,
+    pub total_path_length: f32,
+    /// Paths walked to their end, not counting moves up a queue.
+    pub arrived: u32,
+    /// Visits to stalls that were served.
+    pub served: u32,
+    /// Navigators that left a queue after waiting too long.
+    pub gave_up: u32,
+    /// Navigators that entered the market through a gate.
+    pub entered: u32,
+    /// Navigators that left the market through an exit.
+    pub left: u32,
+}
+
+impl SimulationStats {
+    pub fn average_path_length(&self) -> f32 {
+        if self.paths_found == 0 {
+            0.0
+        } else {
+            self.total_path_length / self.paths_found as f32
+        }
+    }
+}
+
+/// Random positions tried to find a walkable one before giving up on an area.
+const MAX_SPAWN_ATTEMPTS: usize = 1000;
+
+/// Spawns `count` agents at random walkable positions inside `area`, a rectangle on the XZ plane,
+/// alone or in groups. Gives up, with a warning, if no walkable position can be found in it.
+#[allow(clippy::too_many_arguments)]
 pub fn spawn_agents(
-    mut commands: Commands,
+    commands: &mut Commands,
     // mut meshes: ResMut<Assets<Mesh>>,
     // mut materials: ResMut<Assets<StandardMaterial>>,
-    my_materials: &Materials,
-    capsule: &Handle<Mesh>,
-    navmesh: &NavMesh,
+    visuals: Option<(&Materials, &Handle<Mesh>)>,
+    archetypes: &Archetypes,
+    groups: &GroupSettings,
+    navmeshes: &SearchNavMeshes,
+    rng: &mut Rng,
+    area: Rect,
     count: u32,
 ) {
-    let mut rng = Rng::new();
-    for i in 0..count {
-        let transform = loop {
-            let transform = Transform::from_translation(Vec3::new(
-                rng.f32() * MAP_SIZE.0 - MAP_SIZE.0 / 2.0,
+    let size = area.size();
+    let mut spawned = 0;
+    while spawned < count as usize {
+        let group_size = groups.size(rng).min(count as usize - spawned);
+        let position = (0..MAX_SPAWN_ATTEMPTS).find_map(|_| {
+            let position = Vec3::new(
+                area.min.x + rng.f32() * size.x,
                 1.75,
-                rng.f32() * MAP_SIZE.1 - MAP_SIZE.1 / 2.0,
-            ));
-            if navmesh.transformed_is_in_mesh(transform.translation) {
-                break transform;
-            }
+                area.min.y + rng.f32() * size.y,
+            );
+            navmeshes
+                .base()
+                .transformed_is_in_mesh(position)
+                .then_some(position)
+        });
+        let Some(position) = position else {
+            // The area is outside of the map or covered by obstacles.
+            warn!(
+                "No walkable position found in {area:?}, skipping {} agents",
+                count as usize - spawned
+            );
+            return;
         };
+        let transform = Transform::from_translation(position);
+        spawn_group(
+            commands, visuals, archetypes, spawned, transform, group_size, navmeshes, rng,
+        );
+        spawned += group_size;
+    }
+}
 
+/// Spawns an agent of a random archetype with a random shopping list.
+pub fn spawn_shopper<'a>(
+    commands: &'a mut Commands,
+    visuals: Option<(&Materials, &Handle<Mesh>)>,
+    archetypes: &Archetypes,
+    index: usize,
+    transform: Transform,
+    rng: &mut Rng,
+) -> EntityCommands<'a> {
+    let archetype = archetypes.pick(rng);
+    let speed = archetypes.profile(archetype).sample_speed(rng);
+    let navigator = Navigator {
+        speed,
+        // color: colour,
+    };
+    let shopper = Shopper::random(rng);
+    let rng = EntityRng(rng.fork());
+    let mut entity = spawn_navigator(commands, visuals, index, transform, navigator, rng);
+    entity.insert((shopper, archetypes.bundle(archetype), Pace::new(speed)));
+    entity
+}
+
+/// Spawns a single agent, with a capsule mesh if `visuals` are available. `index` picks its
+/// material. Agents with a mesh are drawn interpolated between simulation ticks.
+pub fn spawn_navigator<'a>(
+    commands: &'a mut Commands,
+    visuals: Option<(&Materials, &Handle<Mesh>)>,
+    index: usize,
+    transform: Transform,
+    navigator: Navigator,
+    rng: EntityRng,
+) -> EntityCommands<'a> {
+    if let Some((my_materials, capsule)) = visuals {
         let material =
-            my_materials.unit_materials[i as usize % my_materials.unit_materials.len()].clone();
+            my_materials.unit_materials[index % my_materials.unit_materials.len()].clone();
         commands.spawn((
             PbrBundle {
                 mesh: capsule.clone(),
@@ -60,11 +263,22 @@ pub fn spawn_agents(
                 transform,
                 ..default()
             },
-            Navigator {
-                speed: MOVEMENT_SPEED,
-                // color: colour,
-            },
-        ));
+            navigator,
+            Avoidance::default(),
+            Velocity::default(),
+            Steering::default(),
+            rng,
+            InterpolatedTranslation::new(transform.translation),
+        ))
+    } else {
+        commands.spawn((
+            TransformBundle::from_transform(transform),
+            navigator,
+            Avoidance::default(),
+            Velocity::default(),
+            Steering::default(),
+            rng,
+        ))
     }
 }
 
@@ -80,12 +294,109 @@ fn new_paths(
     }
 }
 
+/// Distance searched around a navigator that just left the navmesh.
+const INITIAL_SEARCH_RADIUS: f32 = 4.0;
+/// Largest distance searched around a navigator outside of the navmesh.
+const MAX_SEARCH_RADIUS: f32 = 512.0;
+
+/// Marks a navigator that is outside of the navmesh, for example after being enclosed by a new
+/// obstacle. The area searched for a way back grows each frame until one is found.
+#[derive(Component)]
+pub struct OffMesh {
+    search_radius: f32,
+}
+
+/// Number of navigators currently stuck outside of the navmesh.
+#[derive(Resource, Default)]
+pub struct StuckNavigators {
+    pub count: usize,
+}
+
+/// Moves navigators that ended up outside of the navmesh back to its closest point, so they can
+/// get a path again.
+pub fn recover_off_mesh(
+    commands: ParallelCommands,
+    mut navigators: Query<
+        (Entity, &mut Transform, Option<&mut OffMesh>),
+        (With<Navigator>, Without<Path>),
+    >,
+    navmeshes: Res<Assets<NavMesh>>,
+    navmesh: Query<&Handle<NavMesh>, Without<ClassNavMesh>>,
+) {
+    let Ok(navmesh_id) = navmesh.get_single() else {
+        return;
+    };
+    let Some(navmesh) = navmeshes.get(navmesh_id) else {
+        return;
+    };
+    navigators
+        .par_iter_mut()
+        .for_each(|(entity, mut transform, off_mesh)| {
+            let recovered = if navmesh.transformed_is_in_mesh(transform.translation) {
+                true
+            } else {
+                let search_radius = off_mesh
+                    .as_ref()
+                    .map_or(INITIAL_SEARCH_RADIUS, |off_mesh| off_mesh.search_radius);
+                if let Some(point) = snap_to_navmesh(navmesh, transform.translation, search_radius)
+                {
+                    transform.translation.x = point.x;
+                    transform.translation.z = point.z;
+                    true
+                } else {
+                    false
+                }
+            };
+
+            match (recovered, off_mesh) {
+                (true, Some(_)) => commands.command_scope(|mut commands| {
+                    commands.entity(entity).remove::<OffMesh>();
+                }),
+                (false, Some(mut off_mesh)) => {
+                    off_mesh.search_radius = (off_mesh.search_radius * 2.0).min(MAX_SEARCH_RADIUS);
+                }
+                (false, None) => commands.command_scope(|mut commands| {
+                    commands.entity(entity).insert(OffMesh {
+                        search_radius: INITIAL_SEARCH_RADIUS * 2.0,
+                    });
+                }),
+                (true, None) => {}
+            }
+        });
+}
+
+fn count_stuck_navigators(
+    mut stuck: ResMut<StuckNavigators>,
+    off_mesh: Query<(), (With<Navigator>, With<OffMesh>)>,
+) {
+    stuck.count = off_mesh.iter().count();
+}
+
+/// Sends the navigators that have nowhere to go and no shopping list to the end of the queue of a
+/// random stall, or to a random point of the map when all stalls are full, closed or unreachable.
+/// [`Shopper`]s choose where to go on their own, and [`Follower`]s walk with their leader.
+#[allow(clippy::too_many_arguments)]
 pub fn give_target_to_navigator(
-    mut commands: ParallelCommands,
-    navigators: Query<(Entity, &Transform), (With<Navigator>, Without<Path>)>,
+    mut commands: Commands,
+    mut navigators: Query<
+        (Entity, &Transform, &mut EntityRng, Option<&SizeClass>),
+        (
+            With<Navigator>,
+            Without<Path>,
+            Without<OffMesh>,
+            Without<WaitingForPath>,
+            Without<Queueing>,
+            Without<Shopper>,
+            Without<Follower>,
+        ),
+    >,
+    stalls: Query<(Entity, &Stall, &StallQueue)>,
     navmeshes: Res<Assets<NavMesh>>,
-    navmesh: Query<&Handle<NavMesh>>,
-    // mut deltas: Local<EntityHashMap<Entity, f32>>,
+    navmesh: Query<&Handle<NavMesh>, Without<ClassNavMesh>>,
+    map_size: Res<MapSize>,
+    time_of_day: Res<TimeOfDay>,
+    mut requests: ResMut<PathRequests>,
+    mut new_requests: Local<Parallel<Vec<(PathRequest, Option<Entity>)>>>,
 ) {
     // let mut rng = Rng::new();
     let Ok(navmesh_id) = navmesh.get_single() else {
@@ -94,118 +405,286 @@ pub fn give_target_to_navigator(
     let Some(navmesh) = navmeshes.get(navmesh_id) else {
         return;
     };
+    let mut open_stalls = stalls
+        .iter()
+        .filter(|(_, stall, queue)| {
+            !queue.is_full() && stall.opening_hours.is_open(time_of_day.hours())
+        })
+        .filter_map(|(entity, _, queue)| Some((entity, queue.entry_point()?)))
+        .collect::<Vec<_>>();
+    // The stall picked should only depend on the random numbers, not on how stalls are stored.
+    open_stalls.sort_unstable_by_key(|(entity, _)| *entity);
+
     // for (entity, transform) in &navigators {
-    navigators.par_iter().for_each(|(entity, transform)| {
-        let mut target;
-        // let delta = if !navmesh.transformed_is_in_mesh(transform.translation) {
-        //     let delta = deltas.entry(entity).or_insert(0.0);
-        //     *delta = *delta + 0.1;
-        //     *delta
-        // } else {
-        //     0.0
-        // };
-        // navmesh.set_delta(delta);
-
-        loop {
-            target = Vec3::new(
-                fastrand::f32() * MAP_SIZE.0 - MAP_SIZE.0 / 2.0,
-                1.75,
-                fastrand::f32() * MAP_SIZE.1 - MAP_SIZE.1 / 2.0,
-            );
+    navigators
+        .par_iter_mut()
+        .for_each(|(entity, transform, mut rng, size_class)| {
+            let (target, stall) = if open_stalls.is_empty() {
+                let mut target;
+                loop {
+                    target = Vec3::new(
+                        rng.0.f32() * map_size.width - map_size.width / 2.0,
+                        1.75,
+                        rng.0.f32() * map_size.depth - map_size.depth / 2.0,
+                    );
 
-            if navmesh.transformed_is_in_mesh(target) {
-                break;
-            }
+                    if navmesh.transformed_is_in_mesh(target) {
+                        break;
+                    }
+                }
+                (target, None)
+            } else {
+                let (stall, entry_point) = open_stalls[rng.0.usize(..open_stalls.len())];
+                (entry_point, Some(stall))
+            };
+
+            new_requests.borrow_local_mut().push((
+                PathRequest {
+                    entity,
+                    from: transform.translation,
+                    to: target,
+                    size_class: size_class.copied().unwrap_or_default(),
+                },
+                stall,
+            ));
+        });
+
+    let mut submitted = Vec::new();
+    new_requests.drain_into(&mut submitted);
+    // The order requests are collected in depends on the threads, not the one they're searched in.
+    submitted.sort_unstable_by_key(|(request, _)| request.entity);
+    for (request, stall) in submitted {
+        let mut entity = commands.entity(request.entity);
+        entity.insert(WaitingForPath);
+        if let Some(stall) = stall {
+            entity.insert(VisitingStall(stall));
         }
+        requests.push(request);
+    }
+}
 
-        if let Some(path) = navmesh.transformed_path(transform.translation, target) {
-            if let Some((first, remaining)) = path.path.split_first() {
-                let mut next = remaining.into_iter().cloned().collect::<Vec<_>>();
-                next.reverse();
-                commands.command_scope(|mut commands| {
-                    commands.entity(entity).insert(Path {
-                        current: *first,
-                        next,
-                    });
-                });
-            }
+/// Areas of the map where obstacles were added, moved or removed since the navmesh of each
+/// [`SizeClass`] was last built. Paths crossing them are replanned once the navmesh of their class
+/// is rebuilt.
+#[derive(Resource, Default)]
+pub struct ChangedAreas {
+    areas: HashMap<SizeClass, Vec<Rect>>,
+    obstacles: EntityHashMap<Entity, Rect>,
+}
+
+fn obstacle_area(obstacle: &PrimitiveObstacle, transform: &Transform) -> Rect {
+    // Shapes without a known size are considered to cover the whole map.
+    let radius = ObstacleShape::from_primitive(obstacle)
+        .map_or(f32::INFINITY, |shape| shape.bounding_radius());
+    Rect::from_center_half_size(transform.translation.xz(), Vec2::splat(radius))
+}
+
+fn track_obstacle_changes(
+    mut changed_areas: ResMut<ChangedAreas>,
+    obstacles: Query<
+        (Entity, &PrimitiveObstacle, &Transform),
+        Or<(Changed<PrimitiveObstacle>, Changed<Transform>)>,
+    >,
+    mut removed: RemovedComponents<PrimitiveObstacle>,
+) {
+    let ChangedAreas {
+        areas,
+        obstacles: known,
+    } = &mut *changed_areas;
+    let mut changed = Vec::new();
+    for entity in removed.read() {
+        if let Some(area) = known.remove(&entity) {
+            changed.push(area);
+        }
+    }
+    for (entity, obstacle, transform) in &obstacles {
+        let area = obstacle_area(obstacle, transform);
+        if let Some(previous) = known.insert(entity, area) {
+            changed.push(previous);
         }
-    });
-}
-
-// pub fn refresh_path<const SIZE: u32, const X: u32, const Y: u32>(
-//     mut commands: Commands,
-//     mut navigator: Query<(Entity, &Transform, &mut Path), With<Navigator>>,
-//     mut navmeshes: ResMut<Assets<NavMesh>>,
-//     navmesh: Query<(&Handle<NavMesh>, Ref<NavMeshStatus>)>,
-//     transforms: Query<&Transform>,
-//     mut deltas: Local<EntityHashMap<Entity, f32>>,
-// ) {
-//     let (navmesh_handle, status) = navmesh.single();
-//     if !status.is_changed() && deltas.is_empty() {
-//         return;
-//     }
-//     let Some(navmesh) = navmeshes.get_mut(navmesh_handle) else {
-//         return;
-//     };
-
-//     for (entity, transform, mut path) in &mut navigator {
-//         let target = transforms.get(path.target).unwrap().translation;
-//         navmesh.set_delta(0.0);
-//         if !navmesh.transformed_is_in_mesh(transform.translation) {
-//             let delta_for_entity = deltas.entry(entity).or_insert(0.0);
-//             *delta_for_entity = *delta_for_entity + 0.1;
-//             navmesh.set_delta(*delta_for_entity);
-//             continue;
-//         }
-//         if !navmesh.transformed_is_in_mesh(target) {
-//             commands.entity(path.target).despawn();
-//             commands.entity(entity).remove::<Path>();
-//             continue;
-//         }
-
-//         let Some(new_path) = navmesh.transformed_path(transform.translation, target) else {
-//             commands.entity(path.target).despawn();
-//             commands.entity(entity).remove::<Path>();
-//             continue;
-//         };
-//         if let Some((first, remaining)) = new_path.path.split_first() {
-//             let mut remaining = remaining.into_iter().cloned().collect::<Vec<_>>();
-//             remaining.reverse();
-//             path.current = *first;
-//             path.next = remaining;
-//             deltas.remove(&entity);
-//         }
-//     }
-// }
+        changed.push(area);
+    }
+    if changed.is_empty() {
+        return;
+    }
+    for class in SizeClass::ALL {
+        areas.entry(class).or_default().extend(&changed);
+    }
+}
+
+/// Replans the paths that go through an area that changed, once the navmesh of their
+/// [`SizeClass`] has been rebuilt. Navigators whose target can't be reached anymore lose their
+/// path and get a new target. Those still waiting for theirs get it searched again on the new
+/// navmesh by [`apply_path_results`].
+pub fn refresh_path(
+    commands: ParallelCommands,
+    mut navigators: Query<(Entity, &Transform, &mut Path, Option<&SizeClass>), With<Navigator>>,
+    navmeshes: PathNavMeshes,
+    status: Query<(&ClassNavMesh, Ref<NavMeshStatus>)>,
+    mut changed_areas: ResMut<ChangedAreas>,
+) {
+    let rebuilt = status
+        .iter()
+        .filter(|(_, status)| status.is_changed() && matches!(**status, NavMeshStatus::Built))
+        .map(|(class, _)| class.0)
+        .collect::<Vec<_>>();
+    if rebuilt.is_empty() {
+        return;
+    }
+    let Some(navmeshes) = navmeshes.get() else {
+        return;
+    };
+    let areas = rebuilt
+        .into_iter()
+        .filter_map(|class| Some((class, changed_areas.areas.remove(&class)?)))
+        .collect::<HashMap<_, _>>();
+    if areas.is_empty() {
+        return;
+    }
+
+    navigators
+        .par_iter_mut()
+        .for_each(|(entity, transform, mut path, size_class)| {
+            let size_class = size_class.copied().unwrap_or_default();
+            let Some(areas) = areas.get(&size_class) else {
+                return;
+            };
+            if !path.crosses(transform.translation, areas) {
+                return;
+            }
+            let new_path = navmeshes
+                .path(size_class, transform.translation, path.target())
+                .and_then(|new_path| Path::from_found(&new_path));
+            match new_path {
+                Some(new_path) => *path = new_path,
+                None => commands.command_scope(|mut commands| {
+                    commands.entity(entity).remove::<Path>();
+                }),
+            }
+        });
+}
+
+/// Sets the preferred velocity of navigators toward the next waypoint of their path, slowing down
+/// when it's the last one.
+pub fn follow_path(
+    mut navigator: Query<(
+        &Transform,
+        Option<&Path>,
+        &Navigator,
+        &Steering,
+        &mut Velocity,
+    )>,
+) {
+    navigator
+        .par_iter_mut()
+        .for_each(|(transform, path, navigator, steering, mut velocity)| {
+            velocity.max_speed = None;
+            velocity.preferred = match path {
+                Some(path) => {
+                    let to_waypoint = path.current.xz() - transform.translation.xz();
+                    let speed = if path.next.is_empty() {
+                        steering.arrival_speed(navigator.speed, to_waypoint.length())
+                    } else {
+                        navigator.speed
+                    };
+                    to_waypoint.normalize_or_zero() * speed
+                }
+                None => Vec2::ZERO,
+            };
+        });
+}
 
 pub fn move_navigator(
     commands: ParallelCommands,
-    mut navigator: Query<(&mut Transform, &mut Path, Entity, &Navigator)>,
+    mut navigator: Query<
+        (
+            &mut Transform,
+            Option<&mut Path>,
+            Entity,
+            &Steering,
+            &Velocity,
+            Option<&SizeClass>,
+            Has<Queueing>,
+        ),
+        With<Navigator>,
+    >,
+    path_navmeshes: PathNavMeshes,
+    flow_fields: Res<FlowFields>,
     time: Res<Time>,
 ) {
+    let search_navmeshes = path_navmeshes.get();
     // for (mut transform, mut path, entity, navigator) in navigator.iter_mut() {
-    navigator
-        .par_iter_mut()
-        .for_each(|(mut transform, mut path, entity, navigator)| {
+    navigator.par_iter_mut().for_each(
+        |(mut transform, path, entity, steering, velocity, class, queueing)| {
             let mut temp_translation = transform.translation;
             temp_translation.y = 0.0;
-            let move_direction = path.current - temp_translation;
-            temp_translation +=
-                move_direction.normalize() * time.delta_seconds() * navigator.speed;
+            let step = velocity.current * time.delta_seconds();
+            temp_translation += Vec3::new(step.x, 0.0, step.y);
             transform.translation.x = temp_translation.x;
             transform.translation.z = temp_translation.z;
-            while temp_translation.distance(path.current) < navigator.speed / 50.0 {
+
+            let Some(mut path) = path else {
+                return;
+            };
+            let class = class.copied().unwrap_or_default();
+            // Navigators on a flow field take the next corner from it as they reach the previous
+            // one, until their target is in sight.
+            if let (Some(key), false) = (path.flow_field, path.next.is_empty()) {
+                let target = path.target();
+                let corner = flow_fields.get(key).and_then(|field| {
+                    if temp_translation.distance(path.current) < steering.corner_radius {
+                        // Navigators in the margin around an obstacle are outside of the field,
+                        // it's sampled from the closest point of the navmesh of their class.
+                        let position = search_navmeshes
+                            .as_ref()
+                            .and_then(|navmeshes| navmeshes.snap(class, temp_translation))?;
+                        field.sample(position, target, steering.corner_radius)
+                    } else {
+                        Some(path.current)
+                    }
+                });
+                match corner {
+                    Some(corner) if corner == target => {
+                        path.current = target;
+                        path.next.clear();
+                    }
+                    Some(corner) => path.current = corner,
+                    // The field was dropped, or the navigator strayed off it. Shoppers search
+                    // their way again, other navigators are given a new target.
+                    None => commands.command_scope(|mut commands| {
+                        commands.entity(entity).remove::<Path>();
+                    }),
+                }
+                return;
+            }
+            // Cut the corner once the waypoint after it can be seen, on the navmesh the path was
+            // searched on so that it keeps clear of obstacles.
+            if let (Some(navmeshes), Some(&next)) = (&search_navmeshes, path.next.last()) {
+                if temp_translation.distance(path.current) < steering.corner_radius
+                    && visible(navmeshes.get(class), temp_translation, next)
+                {
+                    path.current = next;
+                    path.next.pop();
+                }
+            }
+            while temp_translation.distance(path.current) < steering.reached_distance() {
                 if let Some(next) = path.next.pop() {
                     path.current = next;
                 } else {
                     commands.command_scope(|mut commands| {
                         commands.entity(entity).remove::<Path>();
+                        // Moving up a queue isn't a new arrival.
+                        if !queueing {
+                            commands.add(|world: &mut World| {
+                                world.resource_mut::<SimulationStats>().arrived += 1;
+                            });
+                        }
                     });
                     break;
                 }
             }
-        });
+        },
+    );
 }
 
 pub fn display_navigator_path(
@@ -0,0 +1,190 @@
+//! Kinds of navigators walking through the market. Each [`Archetype`] has its own size, walking
+//! speeds and mesh, and paths on the navmesh of the [`SizeClass`] it fits in.
+
+use std::f32::consts::TAU;
+
+use bevy::{prelude::*, utils::HashMap};
+use fastrand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{avoidance::Avoidance, clearance::SizeClass};
+
+pub struct ArchetypePlugin;
+
+impl Plugin for ArchetypePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Archetypes>();
+    }
+}
+
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Archetype {
+    #[default]
+    Pedestrian,
+    /// A shopper pushing a cart.
+    CartShopper,
+    /// A porter pushing a handtruck.
+    Porter,
+    Child,
+}
+
+impl Archetype {
+    pub const ALL: [Archetype; 4] = [
+        Archetype::Pedestrian,
+        Archetype::CartShopper,
+        Archetype::Porter,
+        Archetype::Child,
+    ];
+}
+
+/// How the walking speeds of new navigators are spread, in meters per second.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum SpeedDistribution {
+    /// All navigators walk at the same speed.
+    Fixed(f32),
+    Uniform {
+        min: f32,
+        max: f32,
+    },
+    /// Speeds further than three standard deviations from the mean are cut.
+    Normal {
+        mean: f32,
+        std_dev: f32,
+    },
+}
+
+impl SpeedDistribution {
+    /// A random speed, never negative.
+    pub fn sample(&self, rng: &mut Rng) -> f32 {
+        let speed = match *self {
+            SpeedDistribution::Fixed(speed) => speed,
+            SpeedDistribution::Uniform { min, max } => min + rng.f32() * (max - min),
+            SpeedDistribution::Normal { mean, std_dev } => {
+                // Box-Muller transform, the first number can't be 0.
+                let u = 1.0 - rng.f32();
+                let v = rng.f32();
+                let z = (-2.0 * u.ln()).sqrt() * (TAU * v).cos();
+                mean + z.clamp(-3.0, 3.0) * std_dev
+            }
+        };
+        speed.max(0.0)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ArchetypeProfile {
+    /// Radius of the navigator, used for avoidance and to pick its [`SizeClass`].
+    pub radius: f32,
+    /// Speeds navigators walk at when alone and rested.
+    pub speed: SpeedDistribution,
+    /// Relative share of spawned navigators.
+    pub share: f32,
+}
+
+impl ArchetypeProfile {
+    pub fn size_class(&self) -> SizeClass {
+        SizeClass::for_radius(self.radius)
+    }
+
+    /// A random speed for a new navigator.
+    pub fn sample_speed(&self, rng: &mut Rng) -> f32 {
+        self.speed.sample(rng)
+    }
+}
+
+/// The profile of each [`Archetype`].
+#[derive(Resource, Clone, Debug)]
+pub struct Archetypes {
+    profiles: HashMap<Archetype, ArchetypeProfile>,
+}
+
+impl Default for Archetypes {
+    fn default() -> Self {
+        let profile = |radius, mean, std_dev, share| ArchetypeProfile {
+            radius,
+            speed: SpeedDistribution::Normal { mean, std_dev },
+            share,
+        };
+        Self {
+            profiles: HashMap::from_iter([
+                (Archetype::Pedestrian, profile(0.6, 1.4, 0.2, 0.6)),
+                (Archetype::CartShopper, profile(0.9, 1.1, 0.15, 0.2)),
+                (Archetype::Porter, profile(1.1, 1.0, 0.1, 0.1)),
+                (Archetype::Child, profile(0.4, 1.1, 0.25, 0.1)),
+            ]),
+        }
+    }
+}
+
+impl Archetypes {
+    pub fn profile(&self, archetype: Archetype) -> &ArchetypeProfile {
+        &self.profiles[&archetype]
+    }
+
+    pub fn profile_mut(&mut self, archetype: Archetype) -> &mut ArchetypeProfile {
+        self.profiles
+            .get_mut(&archetype)
+            .expect("all archetypes have a profile")
+    }
+
+    /// A random archetype, following their shares.
+    pub fn pick(&self, rng: &mut Rng) -> Archetype {
+        let total = Archetype::ALL
+            .iter()
+            .map(|archetype| self.profile(*archetype).share.max(0.0))
+            .sum::<f32>();
+        let mut remaining = rng.f32() * total;
+        for archetype in Archetype::ALL {
+            remaining -= self.profile(archetype).share.max(0.0);
+            if remaining < 0.0 {
+                return archetype;
+            }
+        }
+        Archetype::default()
+    }
+
+    /// The components a navigator of `archetype` needs.
+    pub fn bundle(&self, archetype: Archetype) -> impl Bundle {
+        let profile = self.profile(archetype);
+        (
+            archetype,
+            profile.size_class(),
+            Avoidance {
+                radius: profile.radius,
+                ..default()
+            },
+        )
+    }
+}
+
+/// Gives each navigator the mesh of its archetype, when rendering: people are capsules, carts and
+/// handtrucks are boxes.
+pub fn add_archetype_meshes(
+    mut commands: Commands,
+    navigators: Query<(Entity, &Archetype), (Added<Archetype>, With<Handle<Mesh>>)>,
+    archetypes: Res<Archetypes>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut handles: Local<HashMap<Archetype, Handle<Mesh>>>,
+) {
+    if archetypes.is_changed() {
+        handles.clear();
+    }
+    for (entity, archetype) in &navigators {
+        let mesh = handles.entry(*archetype).or_insert_with(|| {
+            let radius = archetypes.profile(*archetype).radius;
+            // Navigators walk 1.75 above the ground, the meshes stand on it.
+            let mesh = match archetype {
+                Archetype::Pedestrian => Mesh::from(Capsule3d::new(radius, 1.75)),
+                Archetype::Child => {
+                    Mesh::from(Capsule3d::new(radius, 0.9)).translated_by(Vec3::NEG_Y * 0.7)
+                }
+                Archetype::CartShopper => Mesh::from(Cuboid::new(radius * 2.0, 1.2, radius * 2.0))
+                    .translated_by(Vec3::NEG_Y * 0.8),
+                Archetype::Porter => Mesh::from(Cuboid::new(radius * 1.6, 2.2, radius * 2.0))
+                    .translated_by(Vec3::NEG_Y * 0.3),
+            };
+            meshes.add(mesh)
+        });
+        commands.entity(entity).insert(mesh.clone());
+    }
+}
@@ -0,0 +1,323 @@
+//! Local collision avoidance between navigators, using optimal reciprocal collision avoidance
+//! (ORCA). Each navigator picks the velocity closest to the one it would like to follow its path
+//! that doesn't collide with its neighbors within [`Avoidance::time_horizon`], assuming they do
+//! their share of the avoidance too.
+
+use bevy::{prelude::*, utils::EntityHashMap};
+
+use crate::{agent3d::Navigator, spatial::SpatialGrid};
+
+const EPSILON: f32 = 0.00001;
+
+/// Settings of the collision avoidance of a navigator.
+#[derive(Component, Clone, Debug)]
+pub struct Avoidance {
+    /// Radius of the navigator.
+    pub radius: f32,
+    /// Other navigators further than this are ignored.
+    pub neighbor_radius: f32,
+    /// How far ahead in seconds collisions are avoided. Larger values react earlier but are more
+    /// restrictive.
+    pub time_horizon: f32,
+    /// Maximum number of neighbors considered, the closest ones are kept.
+    pub max_neighbors: usize,
+}
+
+impl Default for Avoidance {
+    fn default() -> Self {
+        Self {
+            radius: 0.6,
+            neighbor_radius: 10.0,
+            time_horizon: 2.0,
+            max_neighbors: 10,
+        }
+    }
+}
+
+/// Velocity of a navigator on the XZ plane.
+#[derive(Component, Clone, Debug, Default)]
+pub struct Velocity {
+    /// Velocity following the path, ignoring other navigators.
+    pub preferred: Vec2,
+    /// Velocity after avoidance, used to move the navigator.
+    pub current: Vec2,
+    /// Highest speed avoidance may pick this tick, the speed of the [`Navigator`] when `None`.
+    /// Reset each tick by [`follow_path`](crate::agent3d::follow_path).
+    pub max_speed: Option<f32>,
+}
+
+/// A half plane of allowed velocities, on the left of `direction` going through `point`.
+#[derive(Clone, Copy)]
+struct Line {
+    point: Vec2,
+    direction: Vec2,
+}
+
+struct Neighbor {
+    entity: Entity,
+    position: Vec2,
+    velocity: Vec2,
+    radius: f32,
+}
+
+pub fn avoid_collisions(
+    mut navigators: Query<(Entity, &Transform, &Navigator, &Avoidance, &mut Velocity)>,
+    grid: Res<SpatialGrid>,
+    time: Res<Time>,
+) {
+    let delta = time.delta_seconds();
+    if delta <= 0.0 {
+        return;
+    }
+
+    // Velocities are updated in place, keep the ones from the previous frame for the neighbors.
+    let previous = navigators
+        .iter()
+        .map(|(entity, _, _, avoidance, velocity)| (entity, (velocity.current, avoidance.radius)))
+        .collect::<EntityHashMap<_, _>>();
+
+    navigators.par_iter_mut().for_each(
+        |(entity, transform, navigator, avoidance, mut velocity)| {
+            let position = transform.translation.xz();
+            let mut close = grid
+                .query_radius(position, avoidance.neighbor_radius)
+                .filter(|entry| entry.entity != entity)
+                .filter_map(|entry| {
+                    let &(velocity, radius) = previous.get(&entry.entity)?;
+                    Some(Neighbor {
+                        entity: entry.entity,
+                        position: entry.position,
+                        velocity,
+                        radius,
+                    })
+                })
+                .collect::<Vec<_>>();
+            // Ties are broken by entity so the order of the lines, and the velocity found,
+            // doesn't depend on the order navigators are stored in.
+            close.sort_unstable_by(|a, b| {
+                a.position
+                    .distance_squared(position)
+                    .total_cmp(&b.position.distance_squared(position))
+                    .then(a.entity.cmp(&b.entity))
+            });
+            close.truncate(avoidance.max_neighbors);
+
+            let lines = close
+                .iter()
+                .map(|neighbor| orca_line(position, velocity.current, avoidance, neighbor, delta))
+                .collect::<Vec<_>>();
+            let max_speed = velocity.max_speed.unwrap_or(navigator.speed);
+            velocity.current = new_velocity(&lines, max_speed, velocity.preferred);
+        },
+    );
+}
+
+/// The half plane of velocities that avoid colliding with `neighbor` within the time horizon,
+/// taking half of the responsibility for it.
+fn orca_line(
+    position: Vec2,
+    velocity: Vec2,
+    avoidance: &Avoidance,
+    neighbor: &Neighbor,
+    delta: f32,
+) -> Line {
+    let relative_position = neighbor.position - position;
+    let relative_velocity = velocity - neighbor.velocity;
+    let distance_squared = relative_position.length_squared();
+    let combined_radius = avoidance.radius + neighbor.radius;
+    let combined_radius_squared = combined_radius * combined_radius;
+
+    let (direction, u) = if distance_squared > combined_radius_squared {
+        // No collision yet.
+        let inverse_time_horizon = 1.0 / avoidance.time_horizon;
+        // Vector from the cutoff center to the relative velocity.
+        let w = relative_velocity - inverse_time_horizon * relative_position;
+        let w_length_squared = w.length_squared();
+        let dot = w.dot(relative_position);
+
+        if dot < 0.0 && dot * dot > combined_radius_squared * w_length_squared {
+            // Project on the cutoff circle.
+            let w_length = w_length_squared.sqrt();
+            let unit_w = w / w_length;
+            (
+                Vec2::new(unit_w.y, -unit_w.x),
+                (combined_radius * inverse_time_horizon - w_length) * unit_w,
+            )
+        } else {
+            // Project on the closest leg.
+            let leg = (distance_squared - combined_radius_squared).sqrt();
+            let direction = if relative_position.perp_dot(w) > 0.0 {
+                Vec2::new(
+                    relative_position.x * leg - relative_position.y * combined_radius,
+                    relative_position.x * combined_radius + relative_position.y * leg,
+                ) / distance_squared
+            } else {
+                -Vec2::new(
+                    relative_position.x * leg + relative_position.y * combined_radius,
+                    -relative_position.x * combined_radius + relative_position.y * leg,
+                ) / distance_squared
+            };
+            let dot = relative_velocity.dot(direction);
+            (direction, dot * direction - relative_velocity)
+        }
+    } else {
+        // Already colliding, separate within this step.
+        let inverse_time_step = 1.0 / delta;
+        let w = relative_velocity - inverse_time_step * relative_position;
+        let w_length = w.length();
+        let unit_w = w.try_normalize().unwrap_or(Vec2::X);
+        (
+            Vec2::new(unit_w.y, -unit_w.x),
+            (combined_radius * inverse_time_step - w_length) * unit_w,
+        )
+    };
+
+    Line {
+        point: velocity + 0.5 * u,
+        direction,
+    }
+}
+
+/// The velocity closest to `preferred` that satisfies all `lines` and is at most `max_speed`,
+/// or the one that violates them the least if there is none.
+fn new_velocity(lines: &[Line], max_speed: f32, preferred: Vec2) -> Vec2 {
+    let (failed, velocity) = linear_program_2(lines, max_speed, preferred, false);
+    if failed < lines.len() {
+        linear_program_3(lines, failed, max_speed, velocity)
+    } else {
+        velocity
+    }
+}
+
+/// Solves the one dimensional problem on line `line_no`, constrained by the lines before it and
+/// the circle of `radius`.
+fn linear_program_1(
+    lines: &[Line],
+    line_no: usize,
+    radius: f32,
+    optimization: Vec2,
+    optimize_direction: bool,
+) -> Option<Vec2> {
+    let line = lines[line_no];
+    let dot = line.point.dot(line.direction);
+    let discriminant = dot * dot + radius * radius - line.point.length_squared();
+    if discriminant < 0.0 {
+        // The circle of max speed fully invalidates this line.
+        return None;
+    }
+
+    let discriminant = discriminant.sqrt();
+    let mut t_left = -dot - discriminant;
+    let mut t_right = -dot + discriminant;
+
+    for other in &lines[..line_no] {
+        let denominator = line.direction.perp_dot(other.direction);
+        let numerator = other.direction.perp_dot(line.point - other.point);
+
+        if denominator.abs() <= EPSILON {
+            // The lines are almost parallel.
+            if numerator < 0.0 {
+                return None;
+            }
+            continue;
+        }
+
+        let t = numerator / denominator;
+        if denominator >= 0.0 {
+            t_right = t_right.min(t);
+        } else {
+            t_left = t_left.max(t);
+        }
+        if t_left > t_right {
+            return None;
+        }
+    }
+
+    let t = if optimize_direction {
+        if optimization.dot(line.direction) > 0.0 {
+            t_right
+        } else {
+            t_left
+        }
+    } else {
+        line.direction
+            .dot(optimization - line.point)
+            .clamp(t_left, t_right)
+    };
+    Some(line.point + t * line.direction)
+}
+
+/// Solves the two dimensional problem. Returns the index of the first line that couldn't be
+/// satisfied, or the number of lines if they all were, and the best velocity found.
+fn linear_program_2(
+    lines: &[Line],
+    radius: f32,
+    optimization: Vec2,
+    optimize_direction: bool,
+) -> (usize, Vec2) {
+    let mut result = if optimize_direction {
+        // The optimization is a unit direction.
+        optimization * radius
+    } else if optimization.length_squared() > radius * radius {
+        optimization.normalize() * radius
+    } else {
+        optimization
+    };
+
+    for (i, line) in lines.iter().enumerate() {
+        if line.direction.perp_dot(line.point - result) > 0.0 {
+            // The result doesn't satisfy this line.
+            match linear_program_1(lines, i, radius, optimization, optimize_direction) {
+                Some(new_result) => result = new_result,
+                None => return (i, result),
+            }
+        }
+    }
+    (lines.len(), result)
+}
+
+/// Finds the velocity minimizing the largest violation of the lines from `begin_line`, when the
+/// two dimensional problem is infeasible.
+fn linear_program_3(lines: &[Line], begin_line: usize, radius: f32, mut result: Vec2) -> Vec2 {
+    let mut distance = 0.0;
+
+    for i in begin_line..lines.len() {
+        let line = lines[i];
+        if line.direction.perp_dot(line.point - result) <= distance {
+            continue;
+        }
+
+        let mut projected_lines = Vec::with_capacity(i);
+        for other in &lines[..i] {
+            let determinant = line.direction.perp_dot(other.direction);
+            let point = if determinant.abs() <= EPSILON {
+                if line.direction.dot(other.direction) > 0.0 {
+                    // The lines are parallel and in the same direction.
+                    continue;
+                }
+                0.5 * (line.point + other.point)
+            } else {
+                line.point
+                    + (other.direction.perp_dot(line.point - other.point) / determinant)
+                        * line.direction
+            };
+            projected_lines.push(Line {
+                point,
+                direction: (other.direction - line.direction).normalize(),
+            });
+        }
+
+        let (failed, new_result) = linear_program_2(
+            &projected_lines,
+            radius,
+            Vec2::new(-line.direction.y, line.direction.x),
+            true,
+        );
+        // This can only fail because of floating point errors, keep the previous result then.
+        if failed == projected_lines.len() {
+            result = new_result;
+        }
+        distance = line.direction.perp_dot(line.point - result);
+    }
+    result
+}
@@ -0,0 +1,374 @@
+//! Navmeshes for navigators of different sizes. Each [`SizeClass`] gets its own navmesh, built
+//! with the obstacles inflated by the clearance of the class, so that large navigators don't path
+//! through gaps only small ones fit in.
+//!
+//! The base navmesh, without inflation, is still the one used to know where navigators can
+//! stand. Paths are searched on the navmesh of the class of the navigator, through
+//! [`PathNavMeshes`], around crowds when [`Congestion`] is measured, and along a [`FlowField`] to
+//! popular destinations. Navigators in the margin around an obstacle, or heading into it, walk to
+//! the closest point of the navmesh of their class.
+
+use std::sync::Arc;
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+use serde::{Deserialize, Serialize};
+use vleue_navigator::{
+    prelude::{NavMeshSettings, NavMeshUpdateMode, PrimitiveObstacle},
+    NavMesh, TransformedPath,
+};
+
+use crate::{
+    congestion::{Congestion, CongestionLayer},
+    flow_field::{FlowField, FlowFieldKey, FlowFields},
+    scenario::ObstacleShape,
+    MapSize,
+};
+
+/// Below this, a miter corner is cut to avoid long spikes at sharp angles.
+const MIN_MITER_COSINE: f32 = 0.5;
+/// How far inside the navmesh points snapped to one of its edges are moved.
+const SNAP_INSET: f32 = 0.01;
+
+pub struct ClearancePlugin;
+
+impl Plugin for ClearancePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(PreUpdate, inflate_obstacles);
+    }
+}
+
+/// How much room a navigator needs around it.
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SizeClass {
+    #[default]
+    Small,
+    Large,
+}
+
+impl SizeClass {
+    pub const ALL: [SizeClass; 2] = [SizeClass::Small, SizeClass::Large];
+
+    /// Distance kept from obstacles by navigators of the class.
+    pub fn clearance(&self) -> f32 {
+        match self {
+            SizeClass::Small => 0.6,
+            SizeClass::Large => 1.1,
+        }
+    }
+
+    /// The smallest class with room for a navigator of `radius`.
+    pub fn for_radius(radius: f32) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|class| radius <= class.clearance())
+            .unwrap_or(SizeClass::Large)
+    }
+}
+
+/// Marks the navmesh built for a size class.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct ClassNavMesh(pub SizeClass);
+
+/// A path found on a navmesh, without its starting point.
+#[derive(Clone, Debug)]
+pub struct FoundPath {
+    pub path: Vec<Vec3>,
+    pub length: f32,
+    /// The field the rest of the path is taken from, when it goes to a popular destination.
+    pub flow_field: Option<FlowFieldKey>,
+}
+
+impl FoundPath {
+    /// The path from `from` through `path`, which doesn't include it.
+    pub fn through(from: Vec3, path: Vec<Vec3>) -> Self {
+        let length = std::iter::once(from)
+            .chain(path.iter().copied())
+            .zip(&path)
+            .map(|(a, b)| a.distance(*b))
+            .sum();
+        Self {
+            path,
+            length,
+            flow_field: None,
+        }
+    }
+}
+
+impl From<TransformedPath> for FoundPath {
+    fn from(path: TransformedPath) -> Self {
+        Self {
+            path: path.path,
+            length: path.length,
+            flow_field: None,
+        }
+    }
+}
+
+/// The navmeshes path searches use. Cheap to clone into a task.
+#[derive(Clone)]
+pub struct SearchNavMeshes {
+    base: NavMesh,
+    classes: Vec<(SizeClass, NavMesh)>,
+    congestion: Vec<(SizeClass, Arc<CongestionLayer>)>,
+    flow_fields: Vec<Arc<FlowField>>,
+}
+
+impl SearchNavMeshes {
+    /// The navmesh without inflation, where navigators can stand.
+    pub fn base(&self) -> &NavMesh {
+        &self.base
+    }
+
+    /// The navmesh of `class`, or the base one until it's built.
+    pub fn get(&self, class: SizeClass) -> &NavMesh {
+        self.classes
+            .iter()
+            .find(|(navmesh_class, _)| *navmesh_class == class)
+            .map_or(&self.base, |(_, navmesh)| navmesh)
+    }
+
+    /// Searches a path for a navigator of `class`. Starts and ends in the margin around an
+    /// obstacle are moved to the closest point of the navmesh of the class, and the path first
+    /// goes there. `None` if they're further than the margin. Paths go around crowds when the
+    /// congestion of the navmesh is known, and along the flow field of `to` when it has one.
+    pub fn path(&self, class: SizeClass, from: Vec3, to: Vec3) -> Option<FoundPath> {
+        let start = self.snap(class, from)?;
+        let to = self.snap(class, to)?;
+        let mut path = self.path_on_navmesh(class, start, to)?;
+        if start != from {
+            path.path.insert(0, start);
+            path.length += from.distance(start);
+        }
+        Some(path)
+    }
+
+    /// The closest point to `point` in the navmesh of `class`, `None` if it's further than the
+    /// margin around obstacles.
+    pub fn snap(&self, class: SizeClass, point: Vec3) -> Option<Vec3> {
+        // The margin is wider at mitered corners.
+        snap_to_navmesh(self.get(class), point, class.clearance() / MIN_MITER_COSINE)
+    }
+
+    fn path_on_navmesh(&self, class: SizeClass, from: Vec3, to: Vec3) -> Option<FoundPath> {
+        let navmesh = self.get(class);
+        if let Some(path) = self
+            .flow_field(class, to)
+            .and_then(|field| field.path(from, to))
+        {
+            return Some(path);
+        }
+        let layer = self
+            .congestion
+            .iter()
+            .find(|(layer_class, layer)| *layer_class == class && layer.is_for(navmesh));
+        match layer {
+            Some((_, layer)) => layer.path(navmesh, from, to),
+            None => navmesh.transformed_path(from, to).map(FoundPath::from),
+        }
+    }
+
+    /// Whether the navmesh of `class` is the same one in `other`.
+    pub fn same_navmesh(&self, other: &SearchNavMeshes, class: SizeClass) -> bool {
+        Arc::ptr_eq(&self.get(class).get(), &other.get(class).get())
+    }
+
+    /// The flow field navigators of `class` heading to `to` share, if it's a popular destination.
+    pub fn flow_field(&self, class: SizeClass, to: Vec3) -> Option<&FlowField> {
+        let key = FlowFieldKey::new(class, to);
+        self.flow_fields
+            .iter()
+            .find(|field| field.key() == key && field.is_for(self.get(class)))
+            .map(Arc::as_ref)
+    }
+}
+
+/// Gives access to the navmeshes of all size classes.
+#[derive(SystemParam)]
+pub struct PathNavMeshes<'w, 's> {
+    assets: Res<'w, Assets<NavMesh>>,
+    base: Query<'w, 's, &'static Handle<NavMesh>, Without<ClassNavMesh>>,
+    classes: Query<'w, 's, (&'static Handle<NavMesh>, &'static ClassNavMesh)>,
+    congestion: Option<Res<'w, Congestion>>,
+    flow_fields: Option<Res<'w, FlowFields>>,
+}
+
+impl PathNavMeshes<'_, '_> {
+    /// The navmeshes, `None` until the base one is built.
+    pub fn get(&self) -> Option<SearchNavMeshes> {
+        let base = self.assets.get(self.base.get_single().ok()?)?.clone();
+        let mut classes = self
+            .classes
+            .iter()
+            .filter_map(|(handle, class)| Some((class.0, self.assets.get(handle)?.clone())))
+            .collect::<Vec<_>>();
+        classes.sort_unstable_by_key(|(class, _)| *class as u8);
+        let congestion = SizeClass::ALL
+            .into_iter()
+            .filter_map(|class| Some((class, self.congestion.as_ref()?.get(class)?.clone())))
+            .collect();
+        let flow_fields = self
+            .flow_fields
+            .as_ref()
+            .map_or_else(Vec::new, |flow_fields| flow_fields.fields());
+        Some(SearchNavMeshes {
+            base,
+            classes,
+            congestion,
+            flow_fields,
+        })
+    }
+}
+
+/// The closest point to `point` in `navmesh`, `point` itself if it's already in it. `None` if it's
+/// further than `max_distance`.
+pub(crate) fn snap_to_navmesh(navmesh: &NavMesh, point: Vec3, max_distance: f32) -> Option<Vec3> {
+    if navmesh.transformed_is_in_mesh(point) {
+        return Some(point);
+    }
+    let to_world = navmesh.transform().compute_matrix();
+    let local = to_world.inverse().transform_point3(point).xy();
+    let mesh = navmesh.get();
+    // Navmeshes built from obstacles have a single layer.
+    let layer = &mesh.layers[0];
+    let coords = |vertex: u32| layer.vertices[vertex as usize].coords;
+    let mut closest: Option<(f32, Vec2)> = None;
+    for polygon in &layer.polygons {
+        let center = polygon.vertices.iter().map(|v| coords(*v)).sum::<Vec2>()
+            / polygon.vertices.len().max(1) as f32;
+        let next = polygon.vertices.iter().cycle().skip(1);
+        for (&a, &b) in polygon.vertices.iter().zip(next) {
+            let (a, b) = (coords(a), coords(b));
+            let along = ((local - a).dot(b - a) / (b - a).length_squared().max(f32::EPSILON))
+                .clamp(0.0, 1.0);
+            let on_edge = a.lerp(b, along);
+            let distance = on_edge.distance(local);
+            if distance <= max_distance && closest.map_or(true, |(best, _)| distance < best) {
+                // Points right on an edge may be outside after rounding.
+                let inside = on_edge + (center - on_edge).normalize_or_zero() * SNAP_INSET;
+                closest = Some((distance, inside));
+            }
+        }
+    }
+    let snapped = to_world.transform_point3(closest?.1.extend(0.0));
+    // Navigators keep walking at the same height.
+    Some(Vec3::new(snapped.x, point.y, snapped.z))
+}
+
+/// Offsets the convex polygon `points`, in counterclockwise order, outward by `distance`.
+fn inflate(points: &[Vec2], distance: f32) -> Vec<Vec2> {
+    let count = points.len();
+    let normal = |from: Vec2, to: Vec2| {
+        let direction = (to - from).normalize_or_zero();
+        Vec2::new(direction.y, -direction.x)
+    };
+    let mut inflated = Vec::with_capacity(count);
+    for i in 0..count {
+        let previous = points[(i + count - 1) % count];
+        let point = points[i];
+        let next = points[(i + 1) % count];
+        let before = normal(previous, point);
+        let after = normal(point, next);
+        let bisector = (before + after).normalize_or_zero();
+        let cosine = bisector.dot(before);
+        if cosine >= MIN_MITER_COSINE {
+            inflated.push(point + bisector * distance / cosine);
+        } else {
+            inflated.push(point + before * distance);
+            inflated.push(point + after * distance);
+        }
+    }
+    inflated
+}
+
+/// Rebuilds the outer edges of the navmesh of each size class, with the inflated obstacles, when
+/// obstacles or the map change.
+#[allow(clippy::type_complexity)]
+fn inflate_obstacles(
+    mut navmeshes: Query<(
+        &ClassNavMesh,
+        &Transform,
+        &mut NavMeshSettings,
+        &mut NavMeshUpdateMode,
+    )>,
+    new_navmeshes: Query<(), Added<ClassNavMesh>>,
+    obstacles: Query<(&PrimitiveObstacle, &Transform)>,
+    changed: Query<
+        (),
+        (
+            With<PrimitiveObstacle>,
+            Or<(Changed<PrimitiveObstacle>, Changed<Transform>)>,
+        ),
+    >,
+    mut removed: RemovedComponents<PrimitiveObstacle>,
+    map_size: Res<MapSize>,
+) {
+    let removed = removed.read().count() > 0;
+    if changed.is_empty() && !removed && new_navmeshes.is_empty() && !map_size.is_changed() {
+        return;
+    }
+    let shapes = obstacles
+        .iter()
+        .filter_map(|(obstacle, transform)| {
+            Some((
+                ObstacleShape::from_primitive(obstacle)?.outline(),
+                *transform,
+            ))
+        })
+        .collect::<Vec<_>>();
+    for (class, navmesh_transform, mut settings, mut update_mode) in &mut navmeshes {
+        let to_navmesh = navmesh_transform.compute_matrix().inverse();
+        let polygons = shapes
+            .iter()
+            .map(|(outline, transform)| {
+                inflate(outline, class.0.clearance())
+                    .into_iter()
+                    .map(|point| {
+                        // Obstacle shapes are on the XZ plane.
+                        let world = transform.transform_point(Vec3::new(point.x, 0.0, point.y));
+                        to_navmesh.transform_point3(world).xy()
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let mut fixed = map_size.outer_edges();
+        fixed.add_obstacles(polygons);
+        settings.fixed = fixed;
+        *update_mode = NavMeshUpdateMode::OnDemand(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::vec2;
+
+    use super::*;
+
+    #[test]
+    fn inflate_moves_square_corners_along_their_bisector() {
+        let square = [
+            vec2(-1.0, -1.0),
+            vec2(1.0, -1.0),
+            vec2(1.0, 1.0),
+            vec2(-1.0, 1.0),
+        ];
+        let inflated = inflate(&square, 1.0);
+        assert_eq!(inflated.len(), 4);
+        for (point, corner) in inflated.iter().zip(square) {
+            assert!(
+                point.abs_diff_eq(corner * 2.0, 1e-4),
+                "{point} from {corner}"
+            );
+        }
+    }
+
+    #[test]
+    fn inflate_cuts_sharp_corners() {
+        let sliver = [vec2(0.0, 0.0), vec2(10.0, 0.0), vec2(0.0, 1.0)];
+        let inflated = inflate(&sliver, 1.0);
+        // The sharp corner at (10, 0) is cut in two, the others are mitered.
+        assert_eq!(inflated.len(), 4);
+        for point in &inflated[1..3] {
+            assert!((point.distance(sliver[1]) - 1.0).abs() < 1e-4);
+        }
+    }
+}
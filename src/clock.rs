@@ -0,0 +1,254 @@
+//! Controls of the simulation clock: the navigators move in [`FixedUpdate`], ticks of
+//! [`SIMULATION_TICK`](crate::SIMULATION_TICK) driven by the virtual time, which can be paused,
+//! stepped one tick at a time, or sped up.
+//!
+//! O pauses and resumes, L runs a single tick while paused, - and = slow down and speed up
+//! through [`SPEED_PRESETS`].
+//!
+//! Rendered navigators are drawn between their positions of the last two ticks, so that movement
+//! stays smooth when frames and ticks don't line up.
+//!
+//! The [`TimeOfDay`] in the market advances with the ticks.
+
+use bevy::{
+    app::{FixedMain, RunFixedMainLoop},
+    prelude::*,
+    time::run_fixed_main_schedule,
+};
+use serde::{Deserialize, Serialize};
+
+/// Speeds the simulation can run at, relative to real time.
+pub const SPEED_PRESETS: [f32; 7] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0, 16.0];
+
+/// Index of the normal speed in [`SPEED_PRESETS`].
+const NORMAL_SPEED: usize = 2;
+/// Hours in a day of the market.
+const HOURS_PER_DAY: f32 = 24.0;
+
+pub struct SimulationClockPlugin;
+
+impl Plugin for SimulationClockPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SimulationClock>()
+            .init_resource::<TimeOfDay>()
+            .add_systems(First, restore_simulated_translation)
+            .add_systems(
+                FixedFirst,
+                (store_previous_translation, advance_time_of_day),
+            )
+            .add_systems(Update, (clock_keys, apply_simulation_clock).chain())
+            .add_systems(
+                RunFixedMainLoop,
+                step_simulation.after(run_fixed_main_schedule),
+            )
+            .add_systems(
+                PostUpdate,
+                interpolate_translation.before(TransformSystem::TransformPropagate),
+            );
+    }
+}
+
+#[derive(Resource, Debug)]
+pub struct SimulationClock {
+    paused: bool,
+    speed: usize,
+    /// Ticks to run on the next frame while paused.
+    pending_steps: u32,
+}
+
+impl Default for SimulationClock {
+    fn default() -> Self {
+        Self {
+            paused: false,
+            speed: NORMAL_SPEED,
+            pending_steps: 0,
+        }
+    }
+}
+
+impl SimulationClock {
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.pending_steps = 0;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        if self.paused {
+            self.resume();
+        } else {
+            self.pause();
+        }
+    }
+
+    /// Runs a single tick on the next frame. Does nothing unless paused.
+    pub fn step(&mut self) {
+        if self.paused {
+            self.pending_steps += 1;
+        }
+    }
+
+    /// Current speed relative to real time.
+    pub fn speed(&self) -> f32 {
+        SPEED_PRESETS[self.speed]
+    }
+
+    /// Switches to the next faster preset, if any.
+    pub fn faster(&mut self) {
+        self.speed = (self.speed + 1).min(SPEED_PRESETS.len() - 1);
+    }
+
+    /// Switches to the next slower preset, if any.
+    pub fn slower(&mut self) {
+        self.speed = self.speed.saturating_sub(1);
+    }
+}
+
+/// Time of day in the market. It advances with the simulated time, faster than real time so that
+/// a whole day can be watched.
+#[derive(Resource, Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimeOfDay {
+    /// Hours since midnight, from 0 to 24.
+    hours: f32,
+    /// Simulated seconds in an hour of the market.
+    pub hour_duration: f32,
+}
+
+impl Default for TimeOfDay {
+    fn default() -> Self {
+        Self {
+            hours: 8.0,
+            hour_duration: 60.0,
+        }
+    }
+}
+
+impl TimeOfDay {
+    pub fn hours(&self) -> f32 {
+        self.hours
+    }
+
+    pub fn set_hours(&mut self, hours: f32) {
+        self.hours = hours.rem_euclid(HOURS_PER_DAY);
+    }
+}
+
+/// Translations of a rendered navigator at the last two ticks. Its [`Transform`] is set between
+/// them for rendering, and restored to the latest one before the simulation runs again.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct InterpolatedTranslation {
+    previous: Vec3,
+    current: Vec3,
+}
+
+impl InterpolatedTranslation {
+    pub fn new(translation: Vec3) -> Self {
+        Self {
+            previous: translation,
+            current: translation,
+        }
+    }
+}
+
+fn clock_keys(input: Res<ButtonInput<KeyCode>>, mut clock: ResMut<SimulationClock>) {
+    if input.just_pressed(KeyCode::KeyO) {
+        clock.toggle_pause();
+        info!(
+            "Simulation {}",
+            if clock.is_paused() {
+                "paused"
+            } else {
+                "resumed"
+            }
+        );
+    }
+    if input.just_pressed(KeyCode::KeyL) {
+        clock.step();
+    }
+    if input.just_pressed(KeyCode::Minus) {
+        clock.slower();
+        info!("Simulation speed: {}x", clock.speed());
+    }
+    if input.just_pressed(KeyCode::Equal) {
+        clock.faster();
+        info!("Simulation speed: {}x", clock.speed());
+    }
+}
+
+fn apply_simulation_clock(clock: Res<SimulationClock>, mut time: ResMut<Time<Virtual>>) {
+    if !clock.is_changed() {
+        return;
+    }
+    if clock.paused {
+        time.pause();
+    } else {
+        time.unpause();
+    }
+    time.set_relative_speed(clock.speed());
+}
+
+/// Runs the ticks asked for with [`SimulationClock::step`]. The virtual time is paused, so the
+/// fixed clock is advanced by hand.
+fn step_simulation(world: &mut World) {
+    let steps = std::mem::take(&mut world.resource_mut::<SimulationClock>().pending_steps);
+    for _ in 0..steps {
+        let mut fixed = world.resource_mut::<Time<Fixed>>();
+        let timestep = fixed.timestep();
+        fixed.advance_by(timestep);
+        *world.resource_mut::<Time>() = world.resource::<Time<Fixed>>().as_generic();
+        world.run_schedule(FixedMain);
+    }
+    if steps > 0 {
+        *world.resource_mut::<Time>() = world.resource::<Time<Virtual>>().as_generic();
+    }
+}
+
+fn advance_time_of_day(mut time_of_day: ResMut<TimeOfDay>, time: Res<Time>) {
+    let hours = time_of_day.hours + time.delta_seconds() / time_of_day.hour_duration;
+    time_of_day.set_hours(hours);
+}
+
+fn restore_simulated_translation(
+    mut navigators: Query<(&mut Transform, &InterpolatedTranslation)>,
+) {
+    navigators
+        .par_iter_mut()
+        .for_each(|(mut transform, interpolated)| {
+            transform.translation = interpolated.current;
+        });
+}
+
+fn store_previous_translation(mut navigators: Query<(&Transform, &mut InterpolatedTranslation)>) {
+    navigators
+        .par_iter_mut()
+        .for_each(|(transform, mut interpolated)| {
+            interpolated.previous = transform.translation;
+        });
+}
+
+fn interpolate_translation(
+    mut navigators: Query<(&mut Transform, &mut InterpolatedTranslation)>,
+    fixed: Res<Time<Fixed>>,
+    clock: Res<SimulationClock>,
+) {
+    // While paused, show where the navigators are after the last step.
+    let fraction = if clock.is_paused() {
+        1.0
+    } else {
+        fixed.overstep_fraction()
+    };
+    navigators
+        .par_iter_mut()
+        .for_each(|(mut transform, mut interpolated)| {
+            interpolated.current = transform.translation;
+            transform.translation = interpolated.previous.lerp(interpolated.current, fraction);
+        });
+}
@@ -0,0 +1,374 @@
+//! Routing around crowds. The density of navigators on each polygon of the navmeshes paths are
+//! searched on is measured regularly, and crossing a polygon costs more the more crowded it is.
+//! New paths then go around jammed aisles instead of all taking the shortest one.
+//!
+//! Congested paths are searched with A* over the [`NavMeshGraph`] of the navmesh, and pulled tight
+//! through the polygons found. When none of the polygons found is crowded, the shortest path is
+//! searched on the navmesh instead. Paths found earlier are only dropped from the [`PathCache`],
+//! and flow fields only rebuilt, when the cost of a polygon changed noticeably.
+//!
+//! Pressing ] toggles [`draw_congestion`], which shows the cost of each polygon of the navmesh of
+//! small navigators, from green when empty to red when crowded.
+
+use std::{collections::BinaryHeap, sync::Arc};
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use vleue_navigator::NavMesh;
+
+use crate::{
+    agent3d::Navigator,
+    clearance::{ClassNavMesh, FoundPath, SizeClass},
+    navmesh_graph::{string_pull, NavMeshGraph, Node},
+    path_cache::PathCache,
+    path_requests::dispatch_path_requests,
+};
+
+/// Cost at which polygons are drawn fully red.
+const MAX_DISPLAYED_COST: f32 = 3.0;
+/// Polygons cheaper than this are as good as empty.
+const UNCROWDED_COST: f32 = 1.01;
+
+pub struct CongestionPlugin;
+
+impl Plugin for CongestionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CongestionSettings>()
+            .init_resource::<Congestion>()
+            .add_systems(
+                FixedUpdate,
+                update_congestion.before(dispatch_path_requests),
+            );
+    }
+}
+
+#[derive(Resource, Clone, Debug)]
+pub struct CongestionSettings {
+    /// Paths only go around crowds when enabled.
+    pub enabled: bool,
+    /// Extra cost of crossing a polygon for each navigator per square meter on it, relative to
+    /// crossing it empty. 0 gives the shortest paths.
+    pub weight: f32,
+    /// Seconds between two measures of the densities.
+    pub update_interval: f32,
+    /// Share of a new measure in the densities, the rest being the previous ones. Lower values
+    /// ignore short lived crowds.
+    pub smoothing: f32,
+    /// Change of the cost of a polygon, since it last changed noticeably, above which cached
+    /// paths crossing it are dropped and flow fields are built again.
+    pub change_threshold: f32,
+}
+
+impl Default for CongestionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            weight: 2.0,
+            update_interval: 1.0,
+            smoothing: 0.5,
+            change_threshold: 0.25,
+        }
+    }
+}
+
+/// The densities on the navmesh of each size class, empty when disabled.
+#[derive(Resource, Default)]
+pub struct Congestion {
+    layers: Vec<(SizeClass, Arc<CongestionLayer>)>,
+    /// Incremented each time the cost of a polygon changed noticeably.
+    epoch: u32,
+}
+
+impl Congestion {
+    /// Changes each time the cost of a polygon changed noticeably, searches made during the same
+    /// epoch find the same paths.
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    pub fn get(&self, class: SizeClass) -> Option<&Arc<CongestionLayer>> {
+        self.layers
+            .iter()
+            .find(|(layer_class, _)| *layer_class == class)
+            .map(|(_, layer)| layer)
+    }
+}
+
+/// Density of navigators on each polygon of a navmesh.
+#[derive(Clone)]
+pub struct CongestionLayer {
+    /// Shared between the measures, it only changes with the navmesh.
+    graph: Arc<NavMeshGraph>,
+    /// Navigators per square meter, by polygon.
+    densities: Vec<f32>,
+    /// Cost of each polygon when it last changed noticeably.
+    reference_costs: Vec<f32>,
+    weight: f32,
+}
+
+impl CongestionLayer {
+    fn new(navmesh: &NavMesh) -> Self {
+        let graph = NavMeshGraph::new(navmesh);
+        Self {
+            densities: vec![0.0; graph.polygons.len()],
+            reference_costs: vec![1.0; graph.polygons.len()],
+            graph: Arc::new(graph),
+            weight: 0.0,
+        }
+    }
+
+    /// Whether the densities were measured on `navmesh`, and not on a previous version of it.
+    pub fn is_for(&self, navmesh: &NavMesh) -> bool {
+        self.graph.is_for(navmesh)
+    }
+
+    pub fn graph(&self) -> &Arc<NavMeshGraph> {
+        &self.graph
+    }
+
+    /// Cost of crossing `polygon`, relative to crossing it empty.
+    pub fn cost(&self, polygon: usize) -> f32 {
+        1.0 + self.weight.max(0.0) * self.densities[polygon]
+    }
+
+    /// Updates the densities from the positions of all navigators, mixed with the previous ones.
+    fn measure(&mut self, positions: impl Iterator<Item = Vec3>, smoothing: f32) {
+        let mut counts = vec![0u32; self.graph.polygons.len()];
+        for position in positions {
+            if let Some(polygon) = self.graph.polygon_at(self.graph.to_navmesh(position)) {
+                counts[polygon] += 1;
+            }
+        }
+        let smoothing = smoothing.clamp(0.0, 1.0);
+        for ((density, count), polygon) in self
+            .densities
+            .iter_mut()
+            .zip(counts)
+            .zip(&self.graph.polygons)
+        {
+            let measured = count as f32 / polygon.area.max(f32::EPSILON);
+            *density += (measured - *density) * smoothing;
+        }
+    }
+
+    /// The polygons whose cost changed by more than `threshold` since they last did.
+    fn take_changes(&mut self, threshold: f32) -> HashSet<u32> {
+        let mut changed = HashSet::default();
+        for polygon in 0..self.densities.len() {
+            let cost = self.cost(polygon);
+            if (cost - self.reference_costs[polygon]).abs() > threshold {
+                self.reference_costs[polygon] = cost;
+                changed.insert(polygon as u32);
+            }
+        }
+        changed
+    }
+
+    /// Searches the cheapest path from `from` to `to` on `navmesh`, `None` if one of them isn't
+    /// on the navmesh or they aren't connected. It's the shortest one when it doesn't cross a
+    /// crowd.
+    pub fn path(&self, navmesh: &NavMesh, from: Vec3, to: Vec3) -> Option<FoundPath> {
+        let shortest = || navmesh.transformed_path(from, to).map(FoundPath::from);
+        if self.weight <= 0.0 {
+            return shortest();
+        }
+        let start = self.graph.to_navmesh(from);
+        let goal = self.graph.to_navmesh(to);
+        let (polygons, portals) = self.corridor(start, goal)?;
+        // The corridor through polygon midpoints isn't always the shortest way.
+        if polygons
+            .iter()
+            .all(|polygon| self.cost(*polygon) < UNCROWDED_COST)
+        {
+            return shortest();
+        }
+        let path = string_pull(start, &portals, goal)
+            .into_iter()
+            .skip(1)
+            .map(|point| self.graph.to_world(point))
+            .collect();
+        Some(FoundPath::through(from, path))
+    }
+
+    /// The polygons crossed by the cheapest path from `start` to `goal`, and the edges between
+    /// them, with A* over the polygons.
+    fn corridor(&self, start: Vec2, goal: Vec2) -> Option<(Vec<usize>, Vec<(Vec2, Vec2)>)> {
+        let polygons = &self.graph.polygons;
+        let start_polygon = self.graph.polygon_at(start)?;
+        let goal_polygon = self.graph.polygon_at(goal)?;
+        // Cost to reach each polygon, where it was entered, and the polygon and portal it was
+        // entered from.
+        let mut best = HashMap::<usize, (f32, Vec2, Option<(usize, usize)>)>::default();
+        best.insert(start_polygon, (0.0, start, None));
+        let mut open = BinaryHeap::new();
+        open.push(Node {
+            estimate: start.distance(goal),
+            cost: 0.0,
+            polygon: start_polygon,
+        });
+        while let Some(Node { cost, polygon, .. }) = open.pop() {
+            let (best_cost, position, _) = best[&polygon];
+            if cost > best_cost {
+                continue;
+            }
+            if polygon == goal_polygon {
+                break;
+            }
+            for (index, portal) in polygons[polygon].portals.iter().enumerate() {
+                let entry = portal.middle();
+                let new_cost = cost + position.distance(entry) * self.cost(polygon);
+                if best
+                    .get(&portal.polygon)
+                    .map_or(true, |(known, ..)| new_cost < *known)
+                {
+                    best.insert(portal.polygon, (new_cost, entry, Some((polygon, index))));
+                    open.push(Node {
+                        estimate: new_cost + entry.distance(goal),
+                        cost: new_cost,
+                        polygon: portal.polygon,
+                    });
+                }
+            }
+        }
+
+        let mut crossed = vec![goal_polygon];
+        let mut portals = Vec::new();
+        let mut polygon = goal_polygon;
+        while let Some((previous, index)) = best.get(&polygon)?.2 {
+            let portal = &polygons[previous].portals[index];
+            portals.push((portal.left, portal.right));
+            crossed.push(previous);
+            polygon = previous;
+        }
+        crossed.reverse();
+        portals.reverse();
+        Some((crossed, portals))
+    }
+}
+
+/// Measures the density on the navmesh of each size class, rebuilding the polygons when the
+/// navmesh changed. Cached paths crossing polygons whose cost changed noticeably are dropped,
+/// they may go through crowds now.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn update_congestion(
+    mut congestion: ResMut<Congestion>,
+    settings: Res<CongestionSettings>,
+    navmeshes: Res<Assets<NavMesh>>,
+    class_navmeshes: Query<(&Handle<NavMesh>, &ClassNavMesh)>,
+    navigators: Query<&Transform, With<Navigator>>,
+    mut cache: ResMut<PathCache>,
+    time: Res<Time>,
+    mut since_update: Local<f32>,
+) {
+    if !settings.enabled {
+        if !congestion.layers.is_empty() {
+            congestion.layers.clear();
+            congestion.epoch = congestion.epoch.wrapping_add(1);
+        }
+        return;
+    }
+    *since_update += time.delta_seconds();
+    if *since_update < settings.update_interval {
+        return;
+    }
+    *since_update = 0.0;
+
+    let mut layers = Vec::new();
+    let mut changed = false;
+    for (handle, class) in &class_navmeshes {
+        let Some(navmesh) = navmeshes.get(handle) else {
+            continue;
+        };
+        let mut layer = match congestion.get(class.0) {
+            Some(layer) if layer.is_for(navmesh) => CongestionLayer::clone(layer),
+            _ => CongestionLayer::new(navmesh),
+        };
+        layer.weight = settings.weight;
+        layer.measure(
+            navigators.iter().map(|transform| transform.translation),
+            settings.smoothing,
+        );
+        let polygons = layer.take_changes(settings.change_threshold);
+        if !polygons.is_empty() {
+            cache.evict_crossing(class.0, &polygons);
+            changed = true;
+        }
+        layers.push((class.0, Arc::new(layer)));
+    }
+    layers.sort_unstable_by_key(|(class, _)| *class as u8);
+    congestion.layers = layers;
+    if changed {
+        congestion.epoch = congestion.epoch.wrapping_add(1);
+    }
+}
+
+/// Draws the outline of each polygon of the navmesh of small navigators, colored by its cost.
+/// Toggled with ].
+pub fn draw_congestion(
+    congestion: Res<Congestion>,
+    input: Res<ButtonInput<KeyCode>>,
+    mut show: Local<bool>,
+    mut gizmos: Gizmos,
+) {
+    if input.just_pressed(KeyCode::BracketRight) {
+        *show = !*show;
+    }
+    if !*show {
+        return;
+    }
+    let Some(layer) = congestion.get(SizeClass::Small) else {
+        return;
+    };
+    for (index, polygon) in layer.graph.polygons.iter().enumerate() {
+        let crowd = ((layer.cost(index) - 1.0) / (MAX_DISPLAYED_COST - 1.0)).clamp(0.0, 1.0);
+        let color = Color::srgb(crowd, 1.0 - crowd, 0.0);
+        let outline = polygon
+            .vertices
+            .iter()
+            .chain(polygon.vertices.first())
+            .map(|vertex| layer.graph.to_world(*vertex) + Vec3::Y * 0.15);
+        gizmos.linestrip(outline, color);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::vec2;
+
+    use super::*;
+
+    #[test]
+    fn corridor_portals_are_oriented_along_the_way() {
+        // A 10 by 10 square with an obstacle in its middle, to go around.
+        let navmesh = NavMesh::from_edge_and_obstacles(
+            vec![
+                vec2(0.0, 0.0),
+                vec2(10.0, 0.0),
+                vec2(10.0, 10.0),
+                vec2(0.0, 10.0),
+            ],
+            vec![vec![
+                vec2(4.0, 4.0),
+                vec2(6.0, 4.0),
+                vec2(6.0, 6.0),
+                vec2(4.0, 6.0),
+            ]],
+        );
+        let layer = CongestionLayer::new(&navmesh);
+        let (start, goal) = (vec2(1.0, 5.0), vec2(9.0, 5.0));
+        let (polygons, portals) = layer.corridor(start, goal).unwrap();
+        assert_eq!(polygons.first().copied(), layer.graph.polygon_at(start));
+        assert_eq!(polygons.last().copied(), layer.graph.polygon_at(goal));
+        assert_eq!(polygons.len(), portals.len() + 1);
+        assert!(!portals.is_empty());
+        let mut from = start;
+        for (left, right) in portals {
+            let middle = (left + right) / 2.0;
+            assert!((middle - from).perp_dot(left - from) > 0.0);
+            assert!((middle - from).perp_dot(right - from) < 0.0);
+            from = middle;
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! Day and night: the sun light turns around the market with the [`TimeOfDay`], and the skybox
+//! dims once it has set.
+
+use std::f32::consts::PI;
+
+use bevy::{core_pipeline::Skybox, pbr::light_consts, prelude::*};
+
+use crate::clock::TimeOfDay;
+
+/// Hour the sun rises, it sets twelve hours later.
+const SUNRISE: f32 = 6.0;
+/// Brightness of the skybox at noon.
+const DAY_SKYBOX_BRIGHTNESS: f32 = 1000.0;
+/// Brightness of the skybox at night, relative to noon.
+const NIGHT_SKYBOX_FACTOR: f32 = 0.05;
+
+pub struct DaylightPlugin;
+
+impl Plugin for DaylightPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, (update_daylight, log_hours));
+    }
+}
+
+fn update_daylight(
+    time_of_day: Res<TimeOfDay>,
+    mut lights: Query<(&mut DirectionalLight, &mut Transform)>,
+    mut skyboxes: Query<&mut Skybox>,
+) {
+    // Angle of the sun over the horizon, from 0 at sunrise to PI at sunset.
+    let angle = (time_of_day.hours() - SUNRISE) / 12.0 * PI;
+    // The sun goes from east to west, a bit to the south.
+    let sun = Vec3::new(angle.cos(), angle.sin(), -0.3).normalize();
+    let daylight = sun.y.max(0.0);
+
+    for (mut light, mut transform) in &mut lights {
+        transform.look_to(-sun, Vec3::Y);
+        light.illuminance = light_consts::lux::AMBIENT_DAYLIGHT * daylight;
+    }
+    for mut skybox in &mut skyboxes {
+        skybox.brightness = DAY_SKYBOX_BRIGHTNESS * daylight.max(NIGHT_SKYBOX_FACTOR);
+    }
+}
+
+fn log_hours(time_of_day: Res<TimeOfDay>, mut last_hour: Local<Option<u32>>) {
+    let hour = time_of_day.hours() as u32;
+    if *last_hour != Some(hour) {
+        *last_hour = Some(hour);
+        info!("Market time: {hour:02}:00");
+    }
+}
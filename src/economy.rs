@@ -0,0 +1,255 @@
+//! Economy of the market. Stalls hold a limited [`Stock`] of the item they sell, restocked over
+//! time, and adjust their price to recent [`Demand`]. Each completed visit where the shopper could
+//! buy sends a [`Transaction`] event, and every sale is recorded in the [`Ledger`] to compare the
+//! revenue of the stalls as the layout of the market changes.
+
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    queue::update_queues,
+    shopping::{ItemKind, Shopper},
+    stall::{dwell_at_stalls, Stall, StallId, VisitCompleted},
+};
+
+/// Number of items a stall holds when not specified.
+pub const DEFAULT_STOCK: u32 = 20;
+/// Time to restock a single item when not specified, in seconds.
+pub const DEFAULT_RESTOCK_TIME: f32 = 10.0;
+
+pub struct EconomyPlugin;
+
+impl Plugin for EconomyPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<EconomySettings>()
+            .init_resource::<Ledger>()
+            .add_event::<Transaction>()
+            .add_systems(
+                FixedUpdate,
+                (restock, sell_to_visitors, record_sales, update_prices)
+                    .chain()
+                    .after(dwell_at_stalls)
+                    .before(update_queues),
+            );
+    }
+}
+
+#[derive(Resource, Clone, Debug)]
+pub struct EconomySettings {
+    /// Time after which a sale counts for half in the demand of a stall, in seconds.
+    pub demand_half_life: f32,
+    /// Demand at which a stall sells at its base price. Prices go up above it and down below it.
+    pub target_demand: f32,
+    /// Change of the price, relative to the base price, for each sale of difference between the
+    /// demand and the target.
+    pub elasticity: f32,
+    /// Lowest price, relative to the base price.
+    pub min_price_factor: f32,
+    /// Highest price, relative to the base price.
+    pub max_price_factor: f32,
+}
+
+impl Default for EconomySettings {
+    fn default() -> Self {
+        Self {
+            demand_half_life: 60.0,
+            target_demand: 3.0,
+            elasticity: 0.1,
+            min_price_factor: 0.5,
+            max_price_factor: 2.0,
+        }
+    }
+}
+
+/// Items left at a stall.
+#[derive(Component, Clone, Debug)]
+pub struct Stock {
+    pub quantity: u32,
+    pub capacity: u32,
+    /// Time to restock a single item, in seconds.
+    pub restock_time: f32,
+    /// Time spent restocking the next item.
+    progress: f32,
+}
+
+impl Stock {
+    /// A full stock.
+    pub fn new(capacity: u32, restock_time: f32) -> Self {
+        Self {
+            quantity: capacity,
+            capacity,
+            restock_time,
+            progress: 0.0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.quantity == 0
+    }
+}
+
+/// Recent sales of a stall, from which its price is set.
+#[derive(Component, Clone, Debug)]
+pub struct Demand {
+    /// Price when the demand is on target.
+    pub base_price: f32,
+    /// Sales, each one decaying with [`EconomySettings::demand_half_life`].
+    recent_sales: f32,
+}
+
+impl Demand {
+    pub fn new(base_price: f32) -> Self {
+        Self {
+            base_price,
+            recent_sales: 0.0,
+        }
+    }
+
+    /// The same demand, with `recent_sales` already made.
+    pub fn with_recent_sales(mut self, recent_sales: f32) -> Self {
+        self.recent_sales = recent_sales;
+        self
+    }
+
+    pub fn recent_sales(&self) -> f32 {
+        self.recent_sales
+    }
+}
+
+/// A sale at a stall.
+#[derive(Event, Clone, Debug)]
+pub struct Transaction {
+    /// Simulated time of the sale, in seconds.
+    pub time: f32,
+    pub stall: Entity,
+    pub stall_id: StallId,
+    pub buyer: Entity,
+    pub item: ItemKind,
+    pub price: f32,
+}
+
+/// A sale as kept in the [`Ledger`], by the id of its stall so that it survives saving and
+/// loading.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Sale {
+    /// Simulated time of the sale, in seconds.
+    pub time: f32,
+    pub stall: StallId,
+    pub item: ItemKind,
+    pub price: f32,
+}
+
+/// Every sale since the simulation started, in the order they happened.
+#[derive(Resource, Default, Debug)]
+pub struct Ledger {
+    sales: Vec<Sale>,
+}
+
+impl Ledger {
+    /// A ledger holding `sales` already made, oldest first.
+    pub fn with_sales(sales: Vec<Sale>) -> Self {
+        Self { sales }
+    }
+
+    pub fn sales(&self) -> &[Sale] {
+        &self.sales
+    }
+
+    pub fn total_revenue(&self) -> f32 {
+        self.sales.iter().map(|sale| sale.price).sum()
+    }
+
+    /// Revenue of `stall` between `from` and `to`, in simulated seconds.
+    pub fn revenue(&self, stall: StallId, from: f32, to: f32) -> f32 {
+        self.sales
+            .iter()
+            .filter(|sale| sale.stall == stall && sale.time >= from && sale.time < to)
+            .map(|sale| sale.price)
+            .sum()
+    }
+
+    /// Total revenue of each stall that sold something.
+    pub fn revenue_by_stall(&self) -> HashMap<StallId, f32> {
+        let mut revenues = HashMap::default();
+        for sale in &self.sales {
+            *revenues.entry(sale.stall).or_default() += sale.price;
+        }
+        revenues
+    }
+}
+
+fn restock(mut stalls: Query<&mut Stock>, time: Res<Time>) {
+    for mut stock in &mut stalls {
+        if stock.quantity >= stock.capacity {
+            stock.progress = 0.0;
+            continue;
+        }
+        stock.progress += time.delta_seconds();
+        while stock.progress >= stock.restock_time && stock.quantity < stock.capacity {
+            stock.progress -= stock.restock_time;
+            stock.quantity += 1;
+        }
+    }
+}
+
+/// Sells their item to the navigators that were served, if it's on their list, in stock and
+/// affordable.
+fn sell_to_visitors(
+    mut visits: EventReader<VisitCompleted>,
+    mut stalls: Query<(&Stall, &StallId, &mut Stock, &mut Demand)>,
+    mut shoppers: Query<&mut Shopper>,
+    mut transactions: EventWriter<Transaction>,
+    time: Res<Time>,
+) {
+    let mut visits = visits.read().collect::<Vec<_>>();
+    // Shoppers served on the same tick buy in the same order on every run.
+    visits.sort_unstable_by_key(|visit| visit.navigator);
+    for visit in visits {
+        let (Ok((stall, stall_id, mut stock, mut demand)), Ok(mut shopper)) = (
+            stalls.get_mut(visit.stall),
+            shoppers.get_mut(visit.navigator),
+        ) else {
+            continue;
+        };
+        if stock.is_empty() || !shopper.wants(stall.sells, stall.price) {
+            continue;
+        }
+        shopper.buy(stall.sells, stall.price);
+        stock.quantity -= 1;
+        demand.recent_sales += 1.0;
+        transactions.send(Transaction {
+            time: time.elapsed_seconds(),
+            stall: visit.stall,
+            stall_id: *stall_id,
+            buyer: visit.navigator,
+            item: stall.sells,
+            price: stall.price,
+        });
+    }
+}
+
+fn record_sales(mut transactions: EventReader<Transaction>, mut ledger: ResMut<Ledger>) {
+    ledger
+        .sales
+        .extend(transactions.read().map(|transaction| Sale {
+            time: transaction.time,
+            stall: transaction.stall_id,
+            item: transaction.item,
+            price: transaction.price,
+        }));
+}
+
+/// Lets the demand decay, and sets the price of each stall from it.
+fn update_prices(
+    mut stalls: Query<(&mut Stall, &mut Demand)>,
+    settings: Res<EconomySettings>,
+    time: Res<Time>,
+) {
+    let decay = 0.5_f32.powf(time.delta_seconds() / settings.demand_half_life);
+    for (mut stall, mut demand) in &mut stalls {
+        demand.recent_sales *= decay;
+        let factor = (1.0 + settings.elasticity * (demand.recent_sales - settings.target_demand))
+            .clamp(settings.min_price_factor, settings.max_price_factor);
+        stall.price = demand.base_price * factor;
+    }
+}
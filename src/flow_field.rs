@@ -0,0 +1,394 @@
+//! Flow fields for destinations many navigators head to. Rather than searching a path for each of
+//! them, the way to the destination is found once from every polygon of the navmesh, and
+//! navigators walk down it: [`move_navigator`](crate::agent3d::move_navigator) takes the next
+//! corner of their path from the field each time they reach the previous one.
+//!
+//! A destination gets a field once [`FlowFieldSettings::min_navigators`] navigators head to it,
+//! and keeps it until fewer than half as many still do. Navigators heading elsewhere search their
+//! own path. Fields go around crowds like paths do when [`Congestion`] is measured.
+
+use std::{collections::BinaryHeap, sync::Arc};
+
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+use vleue_navigator::NavMesh;
+
+use crate::{
+    agent3d::{Navigator, Path},
+    clearance::{FoundPath, PathNavMeshes, SizeClass},
+    congestion::{update_congestion, Congestion, CongestionLayer},
+    navmesh_graph::{string_pull, NavMeshGraph, Node},
+    path_cache::visible,
+    path_requests::apply_path_results,
+    queue::update_queues,
+};
+
+/// Destinations closer than this share their field.
+const DESTINATION_CELL_SIZE: f32 = 1.0;
+
+pub struct FlowFieldPlugin;
+
+impl Plugin for FlowFieldPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<FlowFieldSettings>()
+            .init_resource::<FlowFields>()
+            .add_systems(
+                FixedUpdate,
+                update_flow_fields
+                    .after(update_congestion)
+                    .before(update_queues)
+                    .before(apply_path_results),
+            );
+    }
+}
+
+#[derive(Resource, Clone, Debug)]
+pub struct FlowFieldSettings {
+    /// Navigators only share fields when enabled.
+    pub enabled: bool,
+    /// Navigators heading to the same destination for it to get a field.
+    pub min_navigators: usize,
+    /// Fields kept at most, for the most popular destinations.
+    pub max_fields: usize,
+    /// Polygons ahead of a navigator looked through for its next corner.
+    pub lookahead: usize,
+}
+
+impl Default for FlowFieldSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_navigators: 20,
+            max_fields: 8,
+            lookahead: 16,
+        }
+    }
+}
+
+/// A destination of navigators of a size class, rounded to [`DESTINATION_CELL_SIZE`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FlowFieldKey {
+    cell: IVec2,
+    size_class: SizeClass,
+}
+
+impl FlowFieldKey {
+    pub fn new(size_class: SizeClass, destination: Vec3) -> Self {
+        Self {
+            cell: (destination.xz() / DESTINATION_CELL_SIZE)
+                .floor()
+                .as_ivec2(),
+            size_class,
+        }
+    }
+
+    /// Orders keys the same way on every run.
+    fn sort_key(&self) -> (i32, i32, u8) {
+        (self.cell.x, self.cell.y, self.size_class as u8)
+    }
+}
+
+/// The way to the destination from a polygon.
+#[derive(Clone, Copy, Debug)]
+struct Flow {
+    /// Portal of the polygon the way goes through, `None` in the polygon of the destination.
+    exit: Option<usize>,
+    /// Middle of the exit, or the destination.
+    point: Vec2,
+    /// Length of the way from `point`.
+    length: f32,
+}
+
+/// The way to a destination from every polygon of the navmesh of a size class.
+pub struct FlowField {
+    key: FlowFieldKey,
+    destination: Vec3,
+    graph: Arc<NavMeshGraph>,
+    /// By polygon, `None` where the destination can't be reached from.
+    flows: Vec<Option<Flow>>,
+    lookahead: usize,
+}
+
+impl FlowField {
+    /// Searches the way to `destination` from every polygon, with Dijkstra's algorithm from the
+    /// polygon of the destination. `None` if the destination isn't on the navmesh.
+    fn new(
+        key: FlowFieldKey,
+        destination: Vec3,
+        graph: Arc<NavMeshGraph>,
+        congestion: Option<&CongestionLayer>,
+        lookahead: usize,
+    ) -> Option<Self> {
+        let goal = graph.to_navmesh(destination);
+        let destination_polygon = graph.polygon_at(goal)?;
+        let cost = |polygon| congestion.map_or(1.0, |layer| layer.cost(polygon));
+        let mut costs = vec![f32::INFINITY; graph.polygons.len()];
+        let mut flows = vec![None; graph.polygons.len()];
+        costs[destination_polygon] = 0.0;
+        flows[destination_polygon] = Some(Flow {
+            exit: None,
+            point: goal,
+            length: 0.0,
+        });
+        let mut open = BinaryHeap::new();
+        open.push(Node {
+            estimate: 0.0,
+            cost: 0.0,
+            polygon: destination_polygon,
+        });
+        while let Some(Node {
+            cost: reached,
+            polygon,
+            ..
+        }) = open.pop()
+        {
+            if reached > costs[polygon] {
+                continue;
+            }
+            let Some(flow) = flows[polygon] else {
+                continue;
+            };
+            for portal in &graph.polygons[polygon].portals {
+                let neighbor = portal.polygon;
+                // Coming from the neighbor, the way goes through the same edge.
+                let entry = portal.middle();
+                let step = entry.distance(flow.point);
+                let new_cost = reached + step * cost(polygon);
+                if new_cost >= costs[neighbor] {
+                    continue;
+                }
+                let exit = graph.polygons[neighbor]
+                    .portals
+                    .iter()
+                    .position(|back| back.polygon == polygon);
+                costs[neighbor] = new_cost;
+                flows[neighbor] = Some(Flow {
+                    exit,
+                    point: entry,
+                    length: flow.length + step,
+                });
+                open.push(Node {
+                    estimate: new_cost,
+                    cost: new_cost,
+                    polygon: neighbor,
+                });
+            }
+        }
+        Some(Self {
+            key,
+            destination,
+            graph,
+            flows,
+            lookahead,
+        })
+    }
+
+    pub fn key(&self) -> FlowFieldKey {
+        self.key
+    }
+
+    /// Whether the field was built on `navmesh`, and not on a previous version of it.
+    pub fn is_for(&self, navmesh: &NavMesh) -> bool {
+        self.graph.is_for(navmesh)
+    }
+
+    fn flow_at(&self, point: Vec2) -> Option<Flow> {
+        self.flows[self.graph.polygon_at(point)?]
+    }
+
+    /// Length of the way from `position` to the destination of the field.
+    pub fn length_from(&self, position: Vec3) -> Option<f32> {
+        let start = self.graph.to_navmesh(position);
+        let flow = self.flow_at(start)?;
+        Some(start.distance(flow.point) + flow.length)
+    }
+
+    /// The next corner of the way from `position` to `destination`, the first one at least
+    /// `reach` away that can be walked to in a straight line. `destination` itself once in
+    /// sight. `None` if `position` isn't on the field.
+    pub fn sample(&self, position: Vec3, destination: Vec3, reach: f32) -> Option<Vec3> {
+        let start = self.graph.to_navmesh(position);
+        let goal = self.graph.to_navmesh(destination);
+        let goal_polygon = self.graph.polygon_at(goal);
+        let mut polygon = self.graph.polygon_at(start)?;
+        let mut portals = Vec::new();
+        // The portals looked through are cut short far from the destination, the way then ends
+        // in the middle of the next one.
+        let mut end = None;
+        while Some(polygon) != goal_polygon {
+            let Some(exit) = self.flows[polygon]?.exit else {
+                break;
+            };
+            let portal = &self.graph.polygons[polygon].portals[exit];
+            if portals.len() >= self.lookahead {
+                end = Some(portal.middle());
+                break;
+            }
+            portals.push((portal.left, portal.right));
+            polygon = portal.polygon;
+        }
+        let pulled = string_pull(start, &portals, end.unwrap_or(goal));
+        let last = pulled.len() - 1;
+        let mut corners = pulled
+            .into_iter()
+            .enumerate()
+            .skip(1)
+            .map(|(index, corner)| {
+                if index == last && end.is_none() {
+                    destination
+                } else {
+                    self.graph.to_world(corner)
+                }
+            });
+        let mut waypoint = corners.next()?;
+        for corner in corners {
+            if position.xz().distance(waypoint.xz()) >= reach
+                || !visible(self.graph.navmesh(), position, corner)
+            {
+                break;
+            }
+            waypoint = corner;
+        }
+        Some(waypoint)
+    }
+
+    /// The path from `from` to `to` along the field: its next corner, then `to`.
+    pub fn path(&self, from: Vec3, to: Vec3) -> Option<FoundPath> {
+        let corner = self.sample(from, to, 0.0)?;
+        let path = if corner == to {
+            vec![to]
+        } else {
+            vec![corner, to]
+        };
+        Some(FoundPath {
+            path,
+            length: self.length_from(from)? + self.destination.xz().distance(to.xz()),
+            flow_field: Some(self.key),
+        })
+    }
+}
+
+/// The fields of the popular destinations.
+#[derive(Resource, Default)]
+pub struct FlowFields {
+    fields: Vec<Arc<FlowField>>,
+    graphs: Vec<(SizeClass, Arc<NavMeshGraph>)>,
+    /// [`Congestion::epoch`] the fields were built in.
+    congestion_epoch: u32,
+}
+
+impl FlowFields {
+    pub fn get(&self, key: FlowFieldKey) -> Option<&Arc<FlowField>> {
+        self.fields.iter().find(|field| field.key == key)
+    }
+
+    /// All the fields, to search paths with away from the world.
+    pub fn fields(&self) -> Vec<Arc<FlowField>> {
+        self.fields.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// The graph of `navmesh`, shared with its congestion layer when there's one.
+    fn graph(
+        &mut self,
+        class: SizeClass,
+        navmesh: &NavMesh,
+        congestion: Option<&CongestionLayer>,
+    ) -> Arc<NavMeshGraph> {
+        if let Some(layer) = congestion {
+            return layer.graph().clone();
+        }
+        self.graphs
+            .retain(|(graph_class, graph)| *graph_class != class || graph.is_for(navmesh));
+        if let Some((_, graph)) = self
+            .graphs
+            .iter()
+            .find(|(graph_class, _)| *graph_class == class)
+        {
+            return graph.clone();
+        }
+        let graph = Arc::new(NavMeshGraph::new(navmesh));
+        self.graphs.push((class, graph.clone()));
+        graph
+    }
+}
+
+/// Counts the navigators heading to each destination, and keeps a field for the most popular
+/// ones. Fields are built again when the navmesh changes, or when the cost of a polygon changed
+/// noticeably.
+fn update_flow_fields(
+    mut flow_fields: ResMut<FlowFields>,
+    settings: Res<FlowFieldSettings>,
+    navigators: Query<(Entity, &Path, Option<&SizeClass>), With<Navigator>>,
+    navmeshes: PathNavMeshes,
+    congestion: Res<Congestion>,
+) {
+    if !settings.enabled {
+        if !flow_fields.fields.is_empty() || !flow_fields.graphs.is_empty() {
+            *flow_fields = FlowFields::default();
+        }
+        return;
+    }
+    let Some(navmeshes) = navmeshes.get() else {
+        return;
+    };
+
+    // Navigators heading to each destination, and where the first of them heads exactly.
+    let mut demand = HashMap::<FlowFieldKey, (usize, Entity, Vec3)>::default();
+    for (entity, path, size_class) in &navigators {
+        let target = path.target();
+        let key = FlowFieldKey::new(size_class.copied().unwrap_or_default(), target);
+        let (count, first, destination) = demand.entry(key).or_insert((0, entity, target));
+        *count += 1;
+        if entity < *first {
+            (*first, *destination) = (entity, target);
+        }
+    }
+    let mut popular = demand
+        .into_iter()
+        .filter(|(key, (count, ..))| {
+            // Fields are kept with fewer navigators, so they don't come and go with each arrival.
+            let needed = if flow_fields.get(*key).is_some() {
+                settings.min_navigators / 2
+            } else {
+                settings.min_navigators
+            };
+            *count >= needed.max(1)
+        })
+        .collect::<Vec<_>>();
+    popular.sort_unstable_by(|(a, (a_count, ..)), (b, (b_count, ..))| {
+        b_count.cmp(a_count).then(a.sort_key().cmp(&b.sort_key()))
+    });
+    popular.truncate(settings.max_fields);
+
+    let mut fields = Vec::with_capacity(popular.len());
+    for (key, (_, _, destination)) in popular {
+        let navmesh = navmeshes.get(key.size_class);
+        let layer = congestion
+            .get(key.size_class)
+            .map(Arc::as_ref)
+            .filter(|layer| layer.is_for(navmesh));
+        let existing = flow_fields.get(key).cloned();
+        if let Some(field) = &existing {
+            if field.is_for(navmesh) && flow_fields.congestion_epoch == congestion.epoch() {
+                fields.push(field.clone());
+                continue;
+            }
+        }
+        let graph = flow_fields.graph(key.size_class, navmesh, layer);
+        // Rebuilt fields keep their destination, the navigators on them head there.
+        let destination = existing.map_or(destination, |field| field.destination);
+        if let Some(field) = FlowField::new(key, destination, graph, layer, settings.lookahead) {
+            fields.push(Arc::new(field));
+        }
+    }
+    flow_fields.fields = fields;
+    flow_fields.congestion_epoch = congestion.epoch();
+}
@@ -0,0 +1,297 @@
+//! Gates on the edge of the map, where navigators enter and leave the market. Navigators arrive
+//! through the entrances at a rate following the [`ArrivalCurve`] over the day, and leave through
+//! the closest exit once done shopping.
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    agent3d::SimulationStats,
+    archetype::Archetypes,
+    clearance::PathNavMeshes,
+    clock::TimeOfDay,
+    group::{spawn_group, GroupSettings},
+    MapSize, Materials, MyCapsule, SimulationRng,
+};
+
+/// Distance from the map border of the gates.
+const GATE_INSET: f32 = 5.0;
+/// Width of a gate, navigators enter anywhere along it.
+const GATE_WIDTH: f32 = 4.0;
+/// Random positions tried along a gate before giving up on an arrival.
+const ARRIVAL_ATTEMPTS: u32 = 10;
+
+pub struct GatePlugin;
+
+impl Plugin for GatePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ArrivalCurve>()
+            .add_systems(FixedUpdate, (place_gates, spawn_arrivals).chain());
+    }
+}
+
+/// A side of the map. North is towards positive Z, east towards positive X.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MapSide {
+    North,
+    East,
+    South,
+    West,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GateKind {
+    Entrance,
+    Exit,
+    #[default]
+    EntranceAndExit,
+}
+
+impl GateKind {
+    pub fn is_entrance(&self) -> bool {
+        matches!(self, GateKind::Entrance | GateKind::EntranceAndExit)
+    }
+
+    pub fn is_exit(&self) -> bool {
+        matches!(self, GateKind::Exit | GateKind::EntranceAndExit)
+    }
+}
+
+/// A gate on a side of the map. Its [`Transform`] follows the side when the map is resized.
+#[derive(Component, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Gate {
+    pub side: MapSide,
+    /// Distance along the side from its middle, towards the east or north.
+    #[serde(default)]
+    pub offset: f32,
+    #[serde(default)]
+    pub kind: GateKind,
+}
+
+impl Gate {
+    /// A gate in the middle of each side of the map, used both ways.
+    pub fn default_gates() -> Vec<Gate> {
+        [MapSide::North, MapSide::East, MapSide::South, MapSide::West]
+            .into_iter()
+            .map(|side| Gate {
+                side,
+                offset: 0.0,
+                kind: GateKind::EntranceAndExit,
+            })
+            .collect()
+    }
+
+    /// Where navigators enter and leave through the gate.
+    pub fn position(&self, map_size: &MapSize) -> Vec3 {
+        let half_size = map_size.half_size() - Vec2::splat(GATE_INSET);
+        let along = |half: f32| self.offset.clamp(-half, half);
+        let (x, z) = match self.side {
+            MapSide::North => (along(half_size.x), half_size.y),
+            MapSide::East => (half_size.x, along(half_size.y)),
+            MapSide::South => (along(half_size.x), -half_size.y),
+            MapSide::West => (-half_size.x, along(half_size.y)),
+        };
+        // Navigators walk at this height.
+        Vec3::new(x, 1.75, z)
+    }
+
+    /// Direction along the gate.
+    fn along(&self) -> Vec3 {
+        match self.side {
+            MapSide::North | MapSide::South => Vec3::X,
+            MapSide::East | MapSide::West => Vec3::Z,
+        }
+    }
+}
+
+/// Spawns `gates`, placed on the sides of the map.
+pub fn spawn_gates(commands: &mut Commands, gates: &[Gate], map_size: &MapSize) {
+    for gate in gates {
+        commands.spawn((
+            *gate,
+            TransformBundle::from_transform(Transform::from_translation(gate.position(map_size))),
+        ));
+    }
+}
+
+/// Arrivals per minute of simulated time over the day, interpolated between points. Each arrival
+/// is a navigator alone or a group, following the [`GroupSettings`].
+///
+/// The default profile has a morning rush, a peak at midday and a quieter afternoon, with no
+/// arrivals in the last hour before most stalls close.
+#[derive(Resource, Clone, Debug)]
+pub struct ArrivalCurve {
+    /// Navigators only arrive when enabled.
+    pub enabled: bool,
+    /// Hour of the day and arrivals per minute, sorted by hour. The curve wraps around midnight.
+    pub points: Vec<(f32, f32)>,
+}
+
+impl Default for ArrivalCurve {
+    fn default() -> Self {
+        Self::new(vec![
+            (7.0, 0.0),
+            (8.5, 200.0),
+            (10.0, 80.0),
+            (12.5, 300.0),
+            (14.5, 100.0),
+            (17.0, 120.0),
+            (19.0, 0.0),
+        ])
+    }
+}
+
+impl ArrivalCurve {
+    /// An enabled curve through `points`.
+    pub fn new(points: Vec<(f32, f32)>) -> Self {
+        Self {
+            enabled: true,
+            points,
+        }
+    }
+
+    /// Arrivals per minute at `hours` since midnight.
+    pub fn rate(&self, hours: f32) -> f32 {
+        let (Some(&first), Some(&last)) = (self.points.first(), self.points.last()) else {
+            return 0.0;
+        };
+        let next = self
+            .points
+            .iter()
+            .position(|(hour, _)| *hour > hours)
+            .unwrap_or(self.points.len());
+        // Around midnight, interpolate between the last point of a day and the first of the next.
+        let (before, after) = match next {
+            0 => ((last.0 - 24.0, last.1), first),
+            n if n == self.points.len() => (last, (first.0 + 24.0, first.1)),
+            n => (self.points[n - 1], self.points[n]),
+        };
+        if after.0 <= before.0 {
+            return before.1;
+        }
+        let t = (hours - before.0) / (after.0 - before.0);
+        before.1 + (after.1 - before.1) * t
+    }
+}
+
+/// Moves the gates to their side of the map when it's resized.
+fn place_gates(mut gates: Query<(&Gate, &mut Transform)>, map_size: Res<MapSize>) {
+    if !map_size.is_changed() {
+        return;
+    }
+    for (gate, mut transform) in &mut gates {
+        transform.translation = gate.position(&map_size);
+    }
+}
+
+/// Brings in the navigators arriving during this tick, alone or in a group, each through a random
+/// entrance.
+#[allow(clippy::too_many_arguments)]
+fn spawn_arrivals(
+    mut commands: Commands,
+    gates: Query<(Entity, &Gate, &Transform)>,
+    curve: Res<ArrivalCurve>,
+    time_of_day: Res<TimeOfDay>,
+    time: Res<Time>,
+    navmeshes: PathNavMeshes,
+    mut rng: ResMut<SimulationRng>,
+    mut stats: ResMut<SimulationStats>,
+    archetypes: Res<Archetypes>,
+    groups: Res<GroupSettings>,
+    materials: Option<Res<Materials>>,
+    capsule: Option<Res<MyCapsule>>,
+    // Fraction of a navigator carried to the next tick.
+    mut pending: Local<f32>,
+) {
+    if !curve.enabled {
+        return;
+    }
+    let Some(navmeshes) = navmeshes.get() else {
+        return;
+    };
+    let mut entrances = gates
+        .iter()
+        .filter(|(_, gate, _)| gate.kind.is_entrance())
+        .collect::<Vec<_>>();
+    if entrances.is_empty() {
+        return;
+    }
+    // Entrances are picked the same way on every run.
+    entrances.sort_unstable_by_key(|(entity, ..)| *entity);
+
+    *pending += curve.rate(time_of_day.hours()) * time.delta_seconds() / 60.0;
+    let count = pending.floor();
+    *pending -= count;
+
+    let visuals = materials
+        .as_deref()
+        .zip(capsule.as_deref().map(|capsule| &capsule.handle));
+    for _ in 0..count as u32 {
+        let (_, gate, transform) = entrances[rng.0.usize(..entrances.len())];
+        let position = (0..ARRIVAL_ATTEMPTS)
+            .map(|_| transform.translation + gate.along() * (rng.0.f32() - 0.5) * GATE_WIDTH)
+            .find(|position| navmeshes.base().transformed_is_in_mesh(*position));
+        let Some(position) = position else {
+            continue;
+        };
+        let size = groups.size(&mut rng.0);
+        spawn_group(
+            &mut commands,
+            visuals,
+            &archetypes,
+            stats.entered as usize,
+            Transform::from_translation(position),
+            size,
+            &navmeshes,
+            &mut rng.0,
+        );
+        stats.entered += size as u32;
+    }
+}
+
+/// Gives a flat marker to the gates, when rendering. Entrances are green, exits red, and gates
+/// used both ways yellow.
+pub fn add_gate_meshes(
+    mut commands: Commands,
+    gates: Query<(Entity, &Gate), Added<Gate>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for (entity, gate) in &gates {
+        let color = match gate.kind {
+            GateKind::Entrance => Color::srgb(0.2, 0.8, 0.2),
+            GateKind::Exit => Color::srgb(0.8, 0.2, 0.2),
+            GateKind::EntranceAndExit => Color::srgb(0.8, 0.8, 0.2),
+        };
+        let size = gate.along() * GATE_WIDTH + Vec3::new(1.0, 0.1, 1.0);
+        commands.entity(entity).insert((
+            // The gate is at the navigators height, the marker on the ground.
+            meshes.add(Mesh::from(Cuboid::from_size(size)).translated_by(Vec3::NEG_Y * 1.75)),
+            materials.add(color),
+            VisibilityBundle::default(),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arrival_rate_wraps_around_midnight() {
+        let curve = ArrivalCurve::new(vec![(6.0, 0.0), (22.0, 100.0)]);
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+        assert!(close(curve.rate(14.0), 50.0));
+        assert!(close(curve.rate(22.0), 100.0));
+        // From 22:00 to 6:00 of the next day.
+        assert!(close(curve.rate(23.0), 87.5));
+        assert!(close(curve.rate(0.0), 75.0));
+        assert!(close(curve.rate(2.0), 50.0));
+        assert!(close(curve.rate(6.0), 0.0));
+    }
+
+    #[test]
+    fn arrival_rate_of_an_empty_curve_is_zero() {
+        assert_eq!(ArrivalCurve::new(Vec::new()).rate(12.0), 0.0);
+    }
+}
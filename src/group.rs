@@ -0,0 +1,306 @@
+//! Navigators walking together in small groups, like families and friends. The leader of a group
+//! chooses where to go and plans the path, its followers keep a loose formation around it.
+//!
+//! Where their place in the formation doesn't fit, in a narrow gap between obstacles, followers
+//! walk single file along the trail of the leader, and take their place again once through. A
+//! leader slows down while followers lag behind. Followers whose leader is gone, like when it left
+//! the market, walk to the closest exit on their own.
+
+use std::collections::VecDeque;
+
+use bevy::{prelude::*, utils::Parallel};
+use fastrand::Rng;
+
+use crate::{
+    agent3d::{follow_path, spawn_navigator, spawn_shopper, Navigator},
+    archetype::Archetypes,
+    avoidance::{avoid_collisions, Velocity},
+    clearance::{PathNavMeshes, SearchNavMeshes, SizeClass},
+    pace::Pace,
+    path_cache::visible,
+    shopping::Shopper,
+    EntityRng, Materials,
+};
+
+/// Distance between followers next to each other, and between rows of the formation.
+const FORMATION_SPACING: f32 = 1.5;
+/// Distance between navigators walking single file.
+const SINGLE_FILE_SPACING: f32 = 1.6;
+/// The leader leaves a point of its trail each time it walked this far.
+const TRAIL_STEP: f32 = 0.5;
+/// Points of the trail kept, enough for the largest groups to walk single file.
+const TRAIL_LENGTH: usize = 32;
+/// Followers slow down when closer than this to their place.
+const ARRIVAL_DISTANCE: f32 = 1.0;
+/// Followers walk this much faster than their own speed to catch up with their place.
+const CATCH_UP_FACTOR: f32 = 1.3;
+/// A follower further than this from its place makes its leader wait.
+const STRAGGLER_DISTANCE: f32 = 5.0;
+/// Speed of a leader waiting for its followers, relative to the speed it would walk at.
+const WAITING_SPEED_FACTOR: f32 = 0.4;
+
+pub struct GroupPlugin;
+
+impl Plugin for GroupPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<GroupSettings>().add_systems(
+            FixedUpdate,
+            follow_leaders.after(follow_path).before(avoid_collisions),
+        );
+    }
+}
+
+/// How many navigators come to the market in groups.
+#[derive(Resource, Clone, Debug)]
+pub struct GroupSettings {
+    /// Share of arrivals that are a group rather than a single navigator.
+    pub share: f32,
+    /// Smallest group, leader included.
+    pub min_size: usize,
+    /// Largest group, leader included.
+    pub max_size: usize,
+}
+
+impl Default for GroupSettings {
+    fn default() -> Self {
+        Self {
+            share: 0.4,
+            min_size: 2,
+            max_size: 5,
+        }
+    }
+}
+
+impl GroupSettings {
+    /// A random number of navigators arriving together, 1 when arriving alone.
+    pub fn size(&self, rng: &mut Rng) -> usize {
+        if self.max_size < 2 || rng.f32() >= self.share {
+            return 1;
+        }
+        rng.usize(self.min_size.clamp(2, self.max_size)..=self.max_size)
+    }
+}
+
+/// The navigator leading a group. Its recent positions are kept for its followers to walk single
+/// file.
+#[derive(Component, Clone, Debug)]
+pub struct GroupLeader {
+    /// Direction the leader walks in, on the XZ plane.
+    heading: Vec2,
+    /// Recent positions, the latest first.
+    trail: VecDeque<Vec3>,
+}
+
+impl Default for GroupLeader {
+    fn default() -> Self {
+        Self {
+            heading: Vec2::Y,
+            trail: VecDeque::new(),
+        }
+    }
+}
+
+impl GroupLeader {
+    /// Records the position and heading of the leader.
+    fn track(&mut self, position: Vec3, velocity: Vec2) {
+        if velocity.length_squared() > f32::EPSILON {
+            self.heading = velocity.normalize();
+        }
+        if self
+            .trail
+            .front()
+            .map_or(true, |last| last.distance(position) >= TRAIL_STEP)
+        {
+            self.trail.push_front(position);
+            self.trail.truncate(TRAIL_LENGTH);
+        }
+    }
+
+    /// The point `distance` behind `position` along the trail, or its end if it's shorter.
+    fn behind(&self, position: Vec3, distance: f32) -> Vec3 {
+        let mut remaining = distance;
+        let mut from = position;
+        for point in &self.trail {
+            let step = from.distance(*point);
+            if step >= remaining && step > 0.0 {
+                return from.lerp(*point, remaining / step);
+            }
+            remaining -= step;
+            from = *point;
+        }
+        from
+    }
+
+    /// Where `offset`, in the formation of the group, is when the leader is at `position`.
+    fn formation_position(&self, position: Vec3, offset: Vec2) -> Vec3 {
+        let world = self.heading.perp() * offset.x + self.heading * offset.y;
+        position + Vec3::new(world.x, 0.0, world.y)
+    }
+}
+
+/// A navigator following the leader of its group rather than choosing where to go.
+#[derive(Component, Clone, Debug)]
+pub struct Follower {
+    pub leader: Entity,
+    /// Order of the follower in its group, from 0. Sets its place in the formation, and in the
+    /// line when walking single file.
+    pub rank: usize,
+    single_file: bool,
+}
+
+impl Follower {
+    pub fn new(leader: Entity, rank: usize) -> Self {
+        Self {
+            leader,
+            rank,
+            single_file: false,
+        }
+    }
+
+    /// Whether the follower is walking single file through a gap.
+    pub fn is_single_file(&self) -> bool {
+        self.single_file
+    }
+
+    /// Place in the formation, relative to the leader walking towards positive Y. Followers walk
+    /// in pairs behind the leader.
+    fn offset(&self) -> Vec2 {
+        let side = if self.rank % 2 == 0 { 1.0 } else { -1.0 };
+        let row = (self.rank / 2) as f32;
+        Vec2::new(side, -(row + 0.5)) * FORMATION_SPACING
+    }
+}
+
+/// Spawns `size` shoppers arriving together at `transform`. The first one leads the group, the
+/// others follow it from their place in the formation, moved onto the navmesh of their size
+/// class. They all get the material picked by `index`, so that groups can be told apart.
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_group(
+    commands: &mut Commands,
+    visuals: Option<(&Materials, &Handle<Mesh>)>,
+    archetypes: &Archetypes,
+    index: usize,
+    transform: Transform,
+    size: usize,
+    navmeshes: &SearchNavMeshes,
+    rng: &mut Rng,
+) {
+    let leader = spawn_shopper(commands, visuals, archetypes, index, transform, rng).id();
+    if size <= 1 {
+        return;
+    }
+    let group_leader = GroupLeader::default();
+    for rank in 0..size - 1 {
+        let follower = Follower::new(leader, rank);
+        let place = group_leader.formation_position(transform.translation, follower.offset());
+        let archetype = archetypes.pick(rng);
+        // Places behind a wall start next to the leader instead.
+        let class = archetypes.profile(archetype).size_class();
+        let position = navmeshes
+            .snap(class, place)
+            .or_else(|| navmeshes.snap(class, transform.translation))
+            .unwrap_or(transform.translation);
+        let speed = archetypes.profile(archetype).sample_speed(rng);
+        let navigator = Navigator { speed };
+        let entity_rng = EntityRng(rng.fork());
+        spawn_navigator(
+            commands,
+            visuals,
+            index,
+            transform.with_translation(position),
+            navigator,
+            entity_rng,
+        )
+        .insert((follower, archetypes.bundle(archetype), Pace::new(speed)));
+    }
+    commands.entity(leader).insert(group_leader);
+}
+
+/// Sets the preferred velocity of followers toward their place in the formation, or behind the
+/// leader when it doesn't fit on the navmesh. Leaders wait for the followers lagging behind, and
+/// followers whose leader is gone become shoppers with nothing left to buy, who head out.
+#[allow(clippy::type_complexity)]
+fn follow_leaders(
+    mut commands: Commands,
+    mut leaders: Query<(&Transform, &mut Velocity, &mut GroupLeader), Without<Follower>>,
+    mut followers: Query<(
+        Entity,
+        &Transform,
+        &Navigator,
+        &mut Velocity,
+        &mut Follower,
+        Option<&SizeClass>,
+    )>,
+    navmeshes: PathNavMeshes,
+    mut stragglers: Local<Parallel<Vec<Entity>>>,
+    mut orphans: Local<Parallel<Vec<Entity>>>,
+) {
+    let Some(navmeshes) = navmeshes.get() else {
+        return;
+    };
+    for (transform, velocity, mut leader) in &mut leaders {
+        leader.track(transform.translation, velocity.current);
+    }
+
+    followers.par_iter_mut().for_each(
+        |(entity, transform, navigator, mut velocity, mut follower, size_class)| {
+            let Ok((leader_transform, _, leader)) = leaders.get(follower.leader) else {
+                orphans.borrow_local_mut().push(entity);
+                return;
+            };
+            let navmesh = navmeshes.get(size_class.copied().unwrap_or_default());
+            let position = transform.translation;
+            let leader_position = leader_transform.translation;
+            let place = leader.formation_position(leader_position, follower.offset());
+            // The place must be on the navmesh, and reachable in a straight line.
+            let fits = navmesh.transformed_is_in_mesh(place)
+                && visible(navmesh, leader_position, place)
+                && visible(navmesh, position, place);
+            follower.single_file = !fits;
+            let target = if fits {
+                place
+            } else {
+                leader.behind(
+                    leader_position,
+                    SINGLE_FILE_SPACING * (follower.rank + 1) as f32,
+                )
+            };
+
+            let to_target = target.xz() - position.xz();
+            let distance = to_target.length();
+            let speed = if distance < ARRIVAL_DISTANCE {
+                navigator.speed * distance / ARRIVAL_DISTANCE
+            } else {
+                navigator.speed * CATCH_UP_FACTOR
+            };
+            velocity.preferred = to_target.normalize_or_zero() * speed;
+            // Let avoidance keep the extra speed.
+            velocity.max_speed = Some(navigator.speed * CATCH_UP_FACTOR);
+            if distance > STRAGGLER_DISTANCE {
+                stragglers.borrow_local_mut().push(follower.leader);
+            }
+        },
+    );
+
+    let mut waiting = Vec::new();
+    stragglers.drain_into(&mut waiting);
+    waiting.sort_unstable();
+    waiting.dedup();
+    for leader in waiting {
+        if let Ok((_, mut velocity, _)) = leaders.get_mut(leader) {
+            velocity.preferred *= WAITING_SPEED_FACTOR;
+        }
+    }
+
+    let mut alone = Vec::new();
+    orphans.drain_into(&mut alone);
+    for entity in alone {
+        commands
+            .entity(entity)
+            .remove::<Follower>()
+            .insert(Shopper {
+                shopping_list: Vec::new(),
+                budget: 0.0,
+            });
+    }
+}
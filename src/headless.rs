@@ -0,0 +1,198 @@
+//! Runs the crowd simulation without a window or renderer, for CI and build servers.
+//! Start it with `market --headless [--ticks N] [--agents N] [--path-cache] [scenario]`.
+//!
+//! Runs are deterministic: the same seed, scenario and arguments give the same positions
+//! checksum.
+
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    time::Duration,
+};
+
+use bevy::{
+    app::ScheduleRunnerPlugin, hierarchy::HierarchyPlugin, input::InputPlugin, log::LogPlugin,
+    prelude::*, time::TimeUpdateStrategy, transform::TransformPlugin,
+};
+
+use crate::{
+    agent3d::{spawn_agents, Navigator, SimulationStats, StuckNavigators},
+    archetype::{Archetype, Archetypes},
+    clearance::PathNavMeshes,
+    clock::TimeOfDay,
+    economy::Ledger,
+    flow_field::FlowFields,
+    group::GroupSettings,
+    pace::WalkingStats,
+    path_cache::PathCache,
+    MapSize, MarketConfig, MarketPlugin, SimulationRng, SIMULATION_TICK,
+};
+
+#[derive(Resource, Clone, Debug)]
+pub struct HeadlessSettings {
+    /// Number of ticks to simulate once the agents are spawned.
+    pub ticks: u32,
+    /// Number of agents spawned once the navmesh is built.
+    pub agents: u32,
+}
+
+impl Default for HeadlessSettings {
+    fn default() -> Self {
+        Self {
+            ticks: 3600,
+            agents: 10000,
+        }
+    }
+}
+
+pub struct HeadlessPlugin {
+    pub settings: HeadlessSettings,
+    /// Configuration of the simulation, rendering is always disabled and runs are always
+    /// deterministic.
+    pub config: MarketConfig,
+}
+
+impl Plugin for HeadlessPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins((
+            // Run the ticks back to back, there is no frame to wait for.
+            MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::ZERO)),
+            LogPlugin::default(),
+            AssetPlugin::default(),
+            TransformPlugin,
+            HierarchyPlugin,
+            InputPlugin,
+            MarketPlugin {
+                config: MarketConfig {
+                    render: false,
+                    deterministic: true,
+                    ..self.config.clone()
+                },
+            },
+        ))
+        // Every frame advances time by exactly one simulation tick, no matter how fast the
+        // machine is.
+        .insert_resource(TimeUpdateStrategy::ManualDuration(SIMULATION_TICK))
+        .insert_resource(self.settings.clone())
+        .init_resource::<HeadlessRun>()
+        .add_systems(Update, spawn_headless_agents)
+        .add_systems(Last, count_ticks);
+    }
+}
+
+#[derive(Resource, Default)]
+struct HeadlessRun {
+    spawned: bool,
+    ticks: u32,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn spawn_headless_agents(
+    mut commands: Commands,
+    settings: Res<HeadlessSettings>,
+    mut run: ResMut<HeadlessRun>,
+    navmeshes: PathNavMeshes,
+    map_size: Res<MapSize>,
+    mut rng: ResMut<SimulationRng>,
+    archetypes: Res<Archetypes>,
+    groups: Res<GroupSettings>,
+) {
+    if run.spawned {
+        return;
+    }
+    let Some(navmeshes) = navmeshes.get() else {
+        return;
+    };
+    spawn_agents(
+        &mut commands,
+        None,
+        &archetypes,
+        &groups,
+        &navmeshes,
+        &mut rng.0,
+        map_size.area(),
+        settings.agents,
+    );
+    run.spawned = true;
+    info!("Spawned {} units", settings.agents);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn count_ticks(
+    settings: Res<HeadlessSettings>,
+    mut run: ResMut<HeadlessRun>,
+    stats: Res<SimulationStats>,
+    stuck: Res<StuckNavigators>,
+    cache: Res<PathCache>,
+    flow_fields: Res<FlowFields>,
+    ledger: Res<Ledger>,
+    time_of_day: Res<TimeOfDay>,
+    walking: Res<WalkingStats>,
+    navigators: Query<(Entity, &Transform), With<Navigator>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if !run.spawned {
+        return;
+    }
+    run.ticks += 1;
+    if run.ticks < settings.ticks {
+        return;
+    }
+
+    println!("Ticks simulated: {}", run.ticks);
+    let hours = time_of_day.hours();
+    println!(
+        "Market time: {:02}:{:02}",
+        hours as u32,
+        (hours.fract() * 60.0) as u32
+    );
+    println!("Agents arrived: {}", stats.arrived);
+    println!("Agents stuck off the navmesh: {}", stuck.count);
+    println!(
+        "Stall visits: {} served, {} gave up",
+        stats.served, stats.gave_up
+    );
+    println!("Agents entered through a gate: {}", stats.entered);
+    println!("Agents left through an exit: {}", stats.left);
+    println!(
+        "Sales: {} for a revenue of {:.2}",
+        ledger.sales().len(),
+        ledger.total_revenue()
+    );
+    println!(
+        "Average path length: {:.2} ({} paths)",
+        stats.average_path_length(),
+        stats.paths_found
+    );
+    for archetype in Archetype::ALL {
+        if let Some(speed) = walking.average_speed(archetype) {
+            println!("Average walking speed of {archetype:?}: {speed:.2} m/s");
+        }
+    }
+    if cache.enabled {
+        println!(
+            "Path cache: {} hits, {} misses",
+            cache.hits(),
+            cache.misses()
+        );
+    }
+    println!("Flow fields in use: {}", flow_fields.len());
+    println!(
+        "Positions checksum: {:016x}",
+        positions_checksum(&navigators)
+    );
+    exit.send(AppExit::Success);
+}
+
+/// Hash of the positions of all navigators, equal between two runs only if they are
+/// bit-identical.
+fn positions_checksum(navigators: &Query<(Entity, &Transform), With<Navigator>>) -> u64 {
+    let mut positions = navigators.iter().collect::<Vec<_>>();
+    positions.sort_unstable_by_key(|(entity, _)| *entity);
+    let mut hasher = DefaultHasher::new();
+    for (_, transform) in positions {
+        for coordinate in transform.translation.to_array() {
+            coordinate.to_bits().hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
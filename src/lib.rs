@@ -0,0 +1,536 @@
+//! Crowd simulation of a market, with agents walking around obstacles on a navmesh.
+//!
+//! Add the [`MarketPlugin`] to an app to run it.
+
+use std::{f32::consts::PI, time::Duration};
+
+use agent3d::MovementPlugin;
+use archetype::ArchetypePlugin;
+use bevy::{
+    color::palettes,
+    core_pipeline::Skybox,
+    math::vec2,
+    pbr::{wireframe::Wireframe, NotShadowCaster},
+    prelude::*,
+};
+use camera_controller::{CameraController, CameraControllerPlugin};
+use clearance::{ClassNavMesh, ClearancePlugin};
+use clock::SimulationClockPlugin;
+use congestion::CongestionPlugin;
+use daylight::DaylightPlugin;
+use economy::EconomyPlugin;
+use flow_field::FlowFieldPlugin;
+use fastrand::Rng;
+use gates::{ArrivalCurve, Gate, GatePlugin};
+use group::GroupPlugin;
+use pace::PacePlugin;
+use path_cache::PathCache;
+use path_requests::PathRequestBudget;
+use save::SavePlugin;
+use scenario::ScenarioPlugin;
+use serde::{Deserialize, Serialize};
+use shopping::ShoppingPlugin;
+use spawner::SpawnerPlugin;
+use stall::StallPlugin;
+use steering::SteeringPlugin;
+use vleue_navigator::{
+    prelude::{
+        NavMeshBundle, NavMeshSettings, NavMeshUpdateMode, NavMeshUpdateModeBlocking,
+        NavmeshUpdaterPlugin, PrimitiveObstacle,
+    },
+    NavMesh, Triangulation, VleueNavigatorPlugin,
+};
+
+pub mod agent3d;
+pub mod archetype;
+pub mod avoidance;
+pub mod camera_controller;
+pub mod clearance;
+pub mod clock;
+pub mod congestion;
+pub mod daylight;
+pub mod economy;
+pub mod flow_field;
+pub mod gates;
+pub mod group;
+pub mod headless;
+pub mod navmesh_graph;
+pub mod pace;
+pub mod path_cache;
+pub mod path_requests;
+pub mod queue;
+pub mod save;
+pub mod scenario;
+pub mod shopping;
+pub mod spatial;
+pub mod spawner;
+pub mod stall;
+pub mod steering;
+
+#[derive(Resource)]
+pub struct Navmeshes {
+    pub navmesh: Handle<NavMesh>,
+}
+
+#[derive(Resource)]
+pub struct Materials {
+    pub unit_materials: Vec<Handle<StandardMaterial>>,
+}
+
+/// Size of the market on the XZ plane, centered on the origin.
+///
+/// Changing it rebuilds the ground plane and the outer edges of the navmesh.
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MapSize {
+    pub width: f32,
+    pub depth: f32,
+}
+
+impl Default for MapSize {
+    fn default() -> Self {
+        Self {
+            width: 2000.,
+            depth: 2000.,
+        }
+    }
+}
+
+impl MapSize {
+    pub fn half_size(&self) -> Vec2 {
+        Vec2::new(self.width / 2.0, self.depth / 2.0)
+    }
+
+    /// The whole map as a rectangle on the XZ plane.
+    pub fn area(&self) -> Rect {
+        Rect::from_center_half_size(Vec2::ZERO, self.half_size())
+    }
+
+    /// The navmesh triangulation covering the whole map, without any obstacles.
+    pub fn outer_edges(&self) -> Triangulation {
+        let half_size = self.half_size();
+        Triangulation::from_outer_edges(&[
+            vec2(-half_size.x, -half_size.y),
+            vec2(half_size.x, -half_size.y),
+            vec2(half_size.x, half_size.y),
+            vec2(-half_size.x, half_size.y),
+        ])
+    }
+}
+
+/// Length of a simulation tick. Navigators move in [`FixedUpdate`] by this amount of time.
+pub const SIMULATION_TICK: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+/// Random number generator shared by the simulation systems, seeded from [`MarketConfig::seed`].
+/// Its state is part of the saved simulation.
+///
+/// All randomness of the simulation comes from it, so that runs with the same seed and inputs
+/// are identical.
+#[derive(Resource)]
+pub struct SimulationRng(pub Rng);
+
+impl SimulationRng {
+    /// A new generator for an entity being spawned.
+    pub fn entity_rng(&mut self) -> EntityRng {
+        EntityRng(self.0.fork())
+    }
+}
+
+/// Random number generator of a single entity, forked from the [`SimulationRng`] when it's
+/// spawned. Systems iterating entities in parallel draw from it, so the numbers an entity gets
+/// don't depend on which thread handles it.
+#[derive(Component)]
+pub struct EntityRng(pub Rng);
+
+/// Configuration of the [`MarketPlugin`], available as a resource once the plugin is added.
+#[derive(Resource, Clone, Debug)]
+pub struct MarketConfig {
+    /// Scenario file to load instead of the random market, relative to the `assets` folder.
+    pub scenario: Option<String>,
+    /// Initial size of the map, inserted as the [`MapSize`] resource.
+    pub map_size: MapSize,
+    /// Seed used to place the obstacles at startup.
+    pub seed: u64,
+    /// Number of random obstacles placed at startup.
+    pub obstacles: u32,
+    /// Number of random stalls placed at startup.
+    pub stalls: u32,
+    /// Number of agents spawned on each press of P.
+    pub spawn_batch: u32,
+    /// Bring agents in through the entrances over the day, see [`ArrivalCurve`].
+    pub arrivals: bool,
+    /// Reuse paths found between the same navmesh polygons, see [`PathCache`].
+    pub path_cache: bool,
+    /// Wait for the path searches started during a tick to finish on the next one, so that runs
+    /// with the same seed and inputs are identical. Ticks can be slower.
+    pub deterministic: bool,
+    /// Spawn the ground, agent meshes and navmesh debug display. Needs the render plugins.
+    pub render: bool,
+    /// Spawn a free camera with a skybox and a light. Disable when embedding the market in an
+    /// app that brings its own. Ignored when `render` is disabled.
+    pub camera: bool,
+}
+
+impl Default for MarketConfig {
+    fn default() -> Self {
+        Self {
+            scenario: None,
+            map_size: MapSize::default(),
+            seed: 437894728948239,
+            obstacles: 1000,
+            stalls: 100,
+            spawn_batch: 10000,
+            arrivals: true,
+            path_cache: false,
+            deterministic: false,
+            render: true,
+            camera: true,
+        }
+    }
+}
+
+/// Adds the market crowd simulation: navmesh generation from obstacles, agent spawning and
+/// movement, and optionally its visuals and camera.
+///
+/// This adds the [`VleueNavigatorPlugin`], so it shouldn't be added again by the app.
+#[derive(Default)]
+pub struct MarketPlugin {
+    pub config: MarketConfig,
+}
+
+impl Plugin for MarketPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins((
+            VleueNavigatorPlugin,
+            // Auto update the navmesh.
+            // Obstacles will be entities with the `Obstacle` marker component,
+            // and use the `Aabb` component as the obstacle data source.
+            NavmeshUpdaterPlugin::<PrimitiveObstacle>::default(),
+            SpawnerPlugin,
+            MovementPlugin,
+            ScenarioPlugin,
+            SavePlugin,
+            SimulationClockPlugin,
+            StallPlugin,
+            ShoppingPlugin,
+            EconomyPlugin,
+            GatePlugin,
+            // How the crowd moves.
+            (
+                ClearancePlugin,
+                ArchetypePlugin,
+                GroupPlugin,
+                SteeringPlugin,
+                PacePlugin,
+                CongestionPlugin,
+                FlowFieldPlugin,
+            ),
+        ))
+        .insert_resource(self.config.clone())
+        .insert_resource(self.config.map_size)
+        .insert_resource(SimulationRng(Rng::with_seed(self.config.seed)))
+        .insert_resource(Time::<Fixed>::from_duration(SIMULATION_TICK))
+        .insert_resource(PathRequestBudget {
+            deterministic: self.config.deterministic,
+            ..default()
+        })
+        .insert_resource(PathCache::default().with_enabled(self.config.path_cache))
+        .insert_resource(ArrivalCurve {
+            enabled: self.config.arrivals,
+            ..default()
+        })
+        .add_systems(PreUpdate, resize_navmesh);
+
+        // A scenario brings its own obstacles and navmesh.
+        if self.config.scenario.is_none() {
+            app.add_systems(Startup, setup_navmesh);
+        }
+
+        if self.config.render {
+            app.insert_resource(ChangedMesh {
+                changed: true,
+                old_entity: None,
+                show: false,
+            })
+            .add_systems(PreUpdate, (debug_navmesh, resize_ground))
+            .add_systems(
+                Update,
+                (
+                    stall::add_stall_meshes,
+                    gates::add_gate_meshes,
+                    archetype::add_archetype_meshes,
+                    congestion::draw_congestion,
+                    pace::log_walking_speeds,
+                ),
+            )
+            .add_systems(Startup, setup);
+
+            if self.config.camera {
+                app.add_plugins((CameraControllerPlugin, DaylightPlugin))
+                    .add_systems(Startup, setup_camera);
+            }
+        }
+    }
+}
+
+fn setup_camera(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let camera_controller = CameraController {
+        ..Default::default()
+    };
+
+    let skybox_handle = asset_server.load("Ryfjallet_cubemap_bc7.ktx2");
+
+    commands.spawn((
+        Camera3dBundle {
+            transform: Transform::from_translation(Vec3::new(0., 200., 50.))
+                .looking_at(Vec3::ZERO, Vec3::Y),
+            ..Default::default()
+        },
+        camera_controller,
+        Skybox {
+            image: skybox_handle,
+            brightness: 1000.0,
+        },
+    ));
+
+    commands.spawn(DirectionalLightBundle {
+        directional_light: DirectionalLight {
+            shadows_enabled: true,
+            ..default()
+        },
+        transform: Transform::from_rotation(Quat::from_rotation_x(-std::f32::consts::FRAC_PI_4)),
+        ..default()
+    });
+}
+
+fn setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    map_size: Res<MapSize>,
+) {
+    commands.spawn((
+        PbrBundle {
+            mesh: meshes.add(Plane3d::new(Vec3::Y, map_size.half_size())),
+            material: materials.add(Color::srgb(0.3, 0.5, 0.3)),
+            ..default()
+        },
+        MyGroundPlane,
+    ));
+
+    let mut unit_materials = Vec::new();
+    for colour in COLOURS.iter() {
+        unit_materials.push(materials.add(*colour));
+    }
+
+    commands.insert_resource(Materials { unit_materials });
+    commands.insert_resource(MyCapsule {
+        handle: meshes.add(Capsule3d::new(0.6, 1.75).mesh()),
+    });
+}
+
+/// Spawns the obstacles, the stalls, the gates and the navmesh built from them. This doesn't need
+/// a renderer, so it's shared with the headless mode.
+fn setup_navmesh(
+    mut commands: Commands,
+    config: Res<MarketConfig>,
+    map_size: Res<MapSize>,
+    mut simulation_rng: ResMut<SimulationRng>,
+) {
+    spawn_navmesh(&mut commands, &map_size, &NavmeshOptions::default());
+
+    spawner::spawn_random_obstacles(
+        &mut commands,
+        &mut simulation_rng.0,
+        &map_size,
+        config.obstacles,
+    );
+    stall::spawn_random_stalls(
+        &mut commands,
+        &mut simulation_rng.0,
+        &map_size,
+        config.stalls,
+    );
+    gates::spawn_gates(&mut commands, &Gate::default_gates(), &map_size);
+}
+
+/// Settings of the navmesh generation that can be tuned per scenario.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NavmeshOptions {
+    pub simplify: f32,
+    pub merge_steps: usize,
+}
+
+impl Default for NavmeshOptions {
+    fn default() -> Self {
+        Self {
+            // Starting with a small mesh simplification factor to avoid very small geometry.
+            // Small geometry can make navmesh generation fail due to rounding errors.
+            // This example has round obstacles which can create small details.
+            simplify: 0.,
+            merge_steps: 0,
+        }
+    }
+}
+
+/// Spawns a navmesh covering the map that is rebuilt as soon as obstacles change, and one for each
+/// [`SizeClass`](clearance::SizeClass).
+pub fn spawn_navmesh(commands: &mut Commands, map_size: &MapSize, options: &NavmeshOptions) {
+    commands.spawn(navmesh_bundle(map_size.outer_edges(), options));
+    for class in clearance::SizeClass::ALL {
+        commands.spawn((
+            navmesh_bundle(map_size.outer_edges(), options),
+            ClassNavMesh(class),
+        ));
+    }
+}
+
+fn navmesh_bundle(fixed: Triangulation, options: &NavmeshOptions) -> impl Bundle {
+    (
+        NavMeshBundle {
+            settings: NavMeshSettings {
+                // Define the outer borders of the navmesh.
+                fixed,
+                simplify: options.simplify,
+                // default_delta: 0.4,
+                merge_steps: options.merge_steps,
+                ..default()
+            },
+            // Mark it for update as soon as obstacles are changed.
+            // Other modes can be debounced or manually triggered.
+            update_mode: NavMeshUpdateMode::OnDemand(true),
+            transform: Transform::from_rotation(Quat::from_rotation_x(PI / 2.0)),
+            ..default()
+        },
+        NavMeshUpdateModeBlocking,
+    )
+}
+
+#[derive(Component)]
+pub struct MyGroundPlane;
+
+fn resize_ground(
+    map_size: Res<MapSize>,
+    ground: Query<&Handle<Mesh>, With<MyGroundPlane>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    if !map_size.is_changed() || map_size.is_added() {
+        return;
+    }
+    for handle in &ground {
+        meshes.insert(handle, Plane3d::new(Vec3::Y, map_size.half_size()).into());
+    }
+}
+
+fn resize_navmesh(
+    map_size: Res<MapSize>,
+    mut navmesh: Query<(&mut NavMeshSettings, &mut NavMeshUpdateMode), Without<ClassNavMesh>>,
+) {
+    if !map_size.is_changed() || map_size.is_added() {
+        return;
+    }
+    for (mut settings, mut update_mode) in &mut navmesh {
+        settings.fixed = map_size.outer_edges();
+        *update_mode = NavMeshUpdateMode::OnDemand(true);
+    }
+}
+
+#[derive(Resource)]
+pub struct MyCapsule {
+    pub handle: Handle<Mesh>,
+}
+
+const COLOURS: [Color; 18] = [
+    Color::Srgba(palettes::tailwind::AMBER_400),
+    Color::Srgba(palettes::tailwind::BLUE_400),
+    Color::Srgba(palettes::tailwind::CYAN_400),
+    Color::Srgba(palettes::tailwind::EMERALD_400),
+    Color::Srgba(palettes::tailwind::FUCHSIA_400),
+    Color::Srgba(palettes::tailwind::GREEN_400),
+    Color::Srgba(palettes::tailwind::INDIGO_400),
+    Color::Srgba(palettes::tailwind::LIME_400),
+    Color::Srgba(palettes::tailwind::ORANGE_400),
+    Color::Srgba(palettes::tailwind::PINK_400),
+    Color::Srgba(palettes::tailwind::PURPLE_400),
+    Color::Srgba(palettes::tailwind::RED_400),
+    Color::Srgba(palettes::tailwind::ROSE_400),
+    Color::Srgba(palettes::tailwind::SKY_400),
+    Color::Srgba(palettes::tailwind::STONE_400),
+    Color::Srgba(palettes::tailwind::TEAL_400),
+    Color::Srgba(palettes::tailwind::VIOLET_400),
+    Color::Srgba(palettes::tailwind::YELLOW_400),
+];
+
+#[derive(Resource)]
+pub struct ChangedMesh {
+    pub changed: bool,
+    pub old_entity: Option<Entity>,
+    pub show: bool,
+}
+
+fn debug_navmesh(
+    mut commands: Commands,
+    navmeshes: Res<Assets<NavMesh>>,
+    navmesh: Query<&Handle<NavMesh>, Without<ClassNavMesh>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut changed_mesh: ResMut<ChangedMesh>,
+    input: Res<ButtonInput<KeyCode>>,
+) {
+    if input.just_pressed(KeyCode::BracketLeft) {
+        changed_mesh.show = !changed_mesh.show;
+        changed_mesh.changed = true;
+    }
+    if input.just_pressed(KeyCode::KeyN) {
+        // force refresh the displayed debug navmesh
+        changed_mesh.changed = true;
+    }
+
+    if !changed_mesh.show {
+        if changed_mesh.old_entity.is_some() {
+            commands
+                .entity(changed_mesh.old_entity.unwrap())
+                .despawn_recursive();
+            changed_mesh.old_entity = None;
+        }
+        return;
+    }
+
+    let Ok(navmesh_id) = navmesh.get_single() else {
+        return;
+    };
+    let Some(navmesh) = navmeshes.get(navmesh_id) else {
+        return;
+    };
+
+    if changed_mesh.changed == false {
+        return;
+    }
+
+    changed_mesh.changed = false;
+
+    if let Some(old_entity) = changed_mesh.old_entity {
+        commands.entity(old_entity).despawn_recursive();
+    }
+
+    let mesh = navmesh.to_wireframe_mesh();
+
+    let entity = commands
+        .spawn((
+            PbrBundle {
+                mesh: meshes.add(mesh),
+                material: materials.add(Color::WHITE),
+                transform: Transform::from_translation(Vec3::new(0.0, 0.1, 0.0)),
+                // transform: Transform::from_translation(Vec3::new(
+                //     -(MAP_SIZE.0 as f32) / 2.0,
+                //     0.1,
+                //     -(MAP_SIZE.1 as f32) / 2.0,
+                // )),
+                ..default()
+            },
+            NotShadowCaster,
+            Wireframe,
+        ))
+        .id();
+    changed_mesh.old_entity = Some(entity);
+}
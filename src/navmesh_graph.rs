@@ -0,0 +1,293 @@
+//! The polygons of a navmesh and the edges they share, for the searches the navmesh can't do on
+//! its own: around crowds in [`congestion`](crate::congestion), and from a destination to every
+//! polygon in [`flow_field`](crate::flow_field).
+//!
+//! Polygons are searched going from the middle of an edge to the next, and the paths through them
+//! are pulled tight with [`string_pull`].
+
+use std::{cmp::Ordering, sync::Arc};
+
+use bevy::{prelude::*, utils::HashMap};
+use vleue_navigator::NavMesh;
+
+/// An edge shared with a neighboring polygon, as seen from inside the polygon.
+pub struct Portal {
+    pub polygon: usize,
+    pub left: Vec2,
+    pub right: Vec2,
+}
+
+impl Portal {
+    pub fn middle(&self) -> Vec2 {
+        (self.left + self.right) / 2.0
+    }
+}
+
+pub struct PolygonInfo {
+    pub vertices: Vec<Vec2>,
+    pub area: f32,
+    pub portals: Vec<Portal>,
+}
+
+/// How the polygons of a navmesh connect, in the coordinates of the navmesh.
+pub struct NavMeshGraph {
+    navmesh: NavMesh,
+    to_world: Mat4,
+    to_navmesh: Mat4,
+    pub polygons: Vec<PolygonInfo>,
+}
+
+impl NavMeshGraph {
+    pub fn new(navmesh: &NavMesh) -> Self {
+        let mesh = navmesh.get();
+        // Navmeshes built from obstacles have a single layer.
+        let layer = &mesh.layers[0];
+        let mut edges = HashMap::<(u32, u32), Vec<usize>>::default();
+        for (index, polygon) in layer.polygons.iter().enumerate() {
+            let next = polygon.vertices.iter().cycle().skip(1);
+            for (&a, &b) in polygon.vertices.iter().zip(next) {
+                edges.entry((a.min(b), a.max(b))).or_default().push(index);
+            }
+        }
+        let polygons = layer
+            .polygons
+            .iter()
+            .enumerate()
+            .map(|(index, polygon)| {
+                let coords = |vertex: u32| layer.vertices[vertex as usize].coords;
+                let vertices = polygon
+                    .vertices
+                    .iter()
+                    .map(|v| coords(*v))
+                    .collect::<Vec<_>>();
+                let center = vertices.iter().sum::<Vec2>() / vertices.len().max(1) as f32;
+                let next = polygon.vertices.iter().cycle().skip(1);
+                let portals = polygon
+                    .vertices
+                    .iter()
+                    .zip(next)
+                    .flat_map(|(&a, &b)| {
+                        let edge = (a.min(b), a.max(b));
+                        let (a, b) = (coords(a), coords(b));
+                        // Seen from the center, `b` is on the left when counterclockwise from `a`.
+                        let (left, right) = if (a - center).perp_dot(b - center) > 0.0 {
+                            (b, a)
+                        } else {
+                            (a, b)
+                        };
+                        edges[&edge]
+                            .iter()
+                            .filter(|&&other| other != index)
+                            .map(move |&other| Portal {
+                                polygon: other,
+                                left,
+                                right,
+                            })
+                    })
+                    .collect();
+                PolygonInfo {
+                    area: polygon_area(&vertices),
+                    vertices,
+                    portals,
+                }
+            })
+            .collect();
+        let to_world = navmesh.transform().compute_matrix();
+        Self {
+            navmesh: navmesh.clone(),
+            to_world,
+            to_navmesh: to_world.inverse(),
+            polygons,
+        }
+    }
+
+    /// Whether the graph was built from `navmesh`, and not from a previous version of it.
+    pub fn is_for(&self, navmesh: &NavMesh) -> bool {
+        Arc::ptr_eq(&self.navmesh.get(), &navmesh.get())
+    }
+
+    pub fn navmesh(&self) -> &NavMesh {
+        &self.navmesh
+    }
+
+    /// The polygon `point`, in the coordinates of the navmesh, is in.
+    pub fn polygon_at(&self, point: Vec2) -> Option<usize> {
+        let polygon = self.navmesh.get().get_point_location(point) as usize;
+        (polygon < self.polygons.len()).then_some(polygon)
+    }
+
+    pub fn to_navmesh(&self, position: Vec3) -> Vec2 {
+        self.to_navmesh.transform_point3(position).xy()
+    }
+
+    pub fn to_world(&self, point: Vec2) -> Vec3 {
+        self.to_world.transform_point3(point.extend(0.0))
+    }
+}
+
+/// A polygon waiting to be searched, the one with the lowest estimate first.
+pub struct Node {
+    pub estimate: f32,
+    /// Cost to reach the polygon when it was queued.
+    pub cost: f32,
+    pub polygon: usize,
+}
+
+impl PartialEq for Node {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Node {}
+
+impl PartialOrd for Node {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Node {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .estimate
+            .total_cmp(&self.estimate)
+            .then(other.polygon.cmp(&self.polygon))
+    }
+}
+
+fn polygon_area(vertices: &[Vec2]) -> f32 {
+    let next = vertices.iter().cycle().skip(1);
+    vertices
+        .iter()
+        .zip(next)
+        .map(|(a, b)| a.perp_dot(*b))
+        .sum::<f32>()
+        .abs()
+        / 2.0
+}
+
+/// Pulls the path from `start` to `goal` through `portals`, as `(left, right)` pairs, tight with
+/// the simple stupid funnel algorithm. Returns its corners, `start` and `goal` included.
+pub fn string_pull(start: Vec2, portals: &[(Vec2, Vec2)], goal: Vec2) -> Vec<Vec2> {
+    let portals = std::iter::once((start, start))
+        .chain(portals.iter().copied())
+        .chain(std::iter::once((goal, goal)))
+        .collect::<Vec<_>>();
+    // Positive when `c` is on the left of the line from `a` to `b`.
+    let cross = |a: Vec2, b: Vec2, c: Vec2| (b - a).perp_dot(c - a);
+
+    let mut points = vec![start];
+    let (mut apex, mut left, mut right) = (start, start, start);
+    let (mut apex_index, mut left_index, mut right_index) = (0, 0, 0);
+    let mut i = 1;
+    while i < portals.len() {
+        let (portal_left, portal_right) = portals[i];
+        // Narrow the funnel from the right.
+        if cross(apex, right, portal_right) >= 0.0 {
+            if apex == right || cross(apex, left, portal_right) < 0.0 {
+                right = portal_right;
+                right_index = i;
+            } else {
+                // The right side crossed over the left one, its end is a corner of the path.
+                points.push(left);
+                apex = left;
+                apex_index = left_index;
+                (left, right) = (apex, apex);
+                (left_index, right_index) = (apex_index, apex_index);
+                i = apex_index + 1;
+                continue;
+            }
+        }
+        // Narrow the funnel from the left.
+        if cross(apex, left, portal_left) <= 0.0 {
+            if apex == left || cross(apex, right, portal_left) > 0.0 {
+                left = portal_left;
+                left_index = i;
+            } else {
+                points.push(right);
+                apex = right;
+                apex_index = right_index;
+                (left, right) = (apex, apex);
+                (left_index, right_index) = (apex_index, apex_index);
+                i = apex_index + 1;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    if points.last() != Some(&goal) {
+        points.push(goal);
+    }
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::vec2;
+
+    use super::*;
+
+    /// A 10 by 10 square with a 2 by 2 obstacle in its middle, split in several polygons.
+    fn square_with_obstacle() -> NavMesh {
+        NavMesh::from_edge_and_obstacles(
+            vec![
+                vec2(0.0, 0.0),
+                vec2(10.0, 0.0),
+                vec2(10.0, 10.0),
+                vec2(0.0, 10.0),
+            ],
+            vec![vec![
+                vec2(4.0, 4.0),
+                vec2(6.0, 4.0),
+                vec2(6.0, 6.0),
+                vec2(4.0, 6.0),
+            ]],
+        )
+    }
+
+    #[test]
+    fn string_pull_straight_corridor() {
+        let portals = [2.0, 4.0, 6.0].map(|x| (vec2(x, 1.0), vec2(x, -1.0)));
+        let path = string_pull(Vec2::ZERO, &portals, vec2(10.0, 0.0));
+        assert_eq!(path, vec![Vec2::ZERO, vec2(10.0, 0.0)]);
+    }
+
+    #[test]
+    fn string_pull_l_turn() {
+        // East along y = 0, then north through the top of the corner at x = 3..5.
+        let portals = [
+            (vec2(3.0, 1.0), vec2(3.0, -1.0)),
+            (vec2(3.0, 1.0), vec2(5.0, 1.0)),
+        ];
+        let path = string_pull(Vec2::ZERO, &portals, vec2(4.0, 5.0));
+        assert_eq!(path, vec![Vec2::ZERO, vec2(3.0, 1.0), vec2(4.0, 5.0)]);
+    }
+
+    #[test]
+    fn string_pull_zero_width_portal() {
+        let portals = [(vec2(3.0, 2.0), vec2(3.0, 2.0))];
+        let path = string_pull(Vec2::ZERO, &portals, vec2(6.0, 0.0));
+        assert_eq!(path, vec![Vec2::ZERO, vec2(3.0, 2.0), vec2(6.0, 0.0)]);
+    }
+
+    #[test]
+    fn portals_are_oriented_from_inside_their_polygon() {
+        let graph = NavMeshGraph::new(&square_with_obstacle());
+        assert!(graph.polygons.len() > 1);
+        for polygon in &graph.polygons {
+            let center = polygon.vertices.iter().sum::<Vec2>() / polygon.vertices.len() as f32;
+            for portal in &polygon.portals {
+                let toward = portal.middle() - center;
+                assert!(toward.perp_dot(portal.left - center) > 0.0);
+                assert!(toward.perp_dot(portal.right - center) < 0.0);
+                // The neighbor sees the same edge the other way around.
+                let back = graph.polygons[portal.polygon]
+                    .portals
+                    .iter()
+                    .find(|other| other.left == portal.right && other.right == portal.left);
+                assert!(back.is_some());
+            }
+        }
+    }
+}
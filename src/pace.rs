@@ -0,0 +1,174 @@
+//! How fast navigators walk. Each navigator has a free speed, drawn from the
+//! [`SpeedDistribution`](crate::archetype::SpeedDistribution) of its archetype, and walks slower
+//! than it in dense crowds and once tired by the distance it walked.
+//!
+//! The slowdown with density follows Weidmann's fundamental diagram: the speed drops as the
+//! density rises, down to a stop at the jam density.
+//!
+//! Average walking speeds are recorded by [`Archetype`] in [`WalkingStats`]. Pressing V logs
+//! them.
+
+use std::f32::consts::PI;
+
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    agent3d::{follow_path, move_navigator, Navigator},
+    archetype::Archetype,
+    avoidance::Velocity,
+    spatial::{update_spatial_grid, SpatialGrid},
+};
+
+pub struct PacePlugin;
+
+impl Plugin for PacePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PaceSettings>()
+            .init_resource::<WalkingStats>()
+            .add_systems(
+                FixedUpdate,
+                (
+                    update_pace.after(update_spatial_grid).before(follow_path),
+                    record_walking.after(move_navigator),
+                ),
+            );
+    }
+}
+
+/// How crowds and fatigue slow navigators down.
+#[derive(Resource, Clone, Debug)]
+pub struct PaceSettings {
+    /// Radius around a navigator the crowd density is measured in.
+    pub density_radius: f32,
+    /// Density, in navigators per square meter, at which crowds stop moving.
+    pub jam_density: f32,
+    /// How fast the speed drops as the density rises.
+    pub density_sensitivity: f32,
+    /// Speed in a jam relative to the free speed, so that jams still clear up.
+    pub min_density_factor: f32,
+    /// Share of the free speed lost for each kilometer walked.
+    pub fatigue_per_km: f32,
+    /// Speed of the most tired navigators relative to their free speed.
+    pub min_fatigue_factor: f32,
+}
+
+impl Default for PaceSettings {
+    fn default() -> Self {
+        Self {
+            density_radius: 2.0,
+            // Values from Weidmann's fundamental diagram.
+            jam_density: 5.4,
+            density_sensitivity: 1.913,
+            min_density_factor: 0.1,
+            fatigue_per_km: 0.05,
+            min_fatigue_factor: 0.7,
+        }
+    }
+}
+
+impl PaceSettings {
+    /// Speed relative to the free speed at `density` navigators per square meter.
+    pub fn density_factor(&self, density: f32) -> f32 {
+        if density <= 0.0 {
+            return 1.0;
+        }
+        let factor =
+            1.0 - (-self.density_sensitivity * (1.0 / density - 1.0 / self.jam_density)).exp();
+        factor.clamp(self.min_density_factor, 1.0)
+    }
+
+    /// Speed relative to the free speed after walking `distance` meters.
+    pub fn fatigue_factor(&self, distance: f32) -> f32 {
+        (1.0 - self.fatigue_per_km * distance / 1000.0).clamp(self.min_fatigue_factor, 1.0)
+    }
+}
+
+/// Walking speed of a navigator when alone and rested, and how far it walked. Its
+/// [`Navigator::speed`] is set from them every tick.
+#[derive(Component, Clone, Debug, Serialize, Deserialize)]
+pub struct Pace {
+    pub free_speed: f32,
+    pub distance_walked: f32,
+}
+
+impl Pace {
+    pub fn new(free_speed: f32) -> Self {
+        Self {
+            free_speed,
+            distance_walked: 0.0,
+        }
+    }
+}
+
+/// Distance walked and time spent walking by each [`Archetype`].
+#[derive(Resource, Default, Debug)]
+pub struct WalkingStats {
+    walked: HashMap<Archetype, (f32, f32)>,
+}
+
+impl WalkingStats {
+    /// Average speed navigators of `archetype` walked at, `None` if none walked yet.
+    pub fn average_speed(&self, archetype: Archetype) -> Option<f32> {
+        let (distance, time) = self.walked.get(&archetype)?;
+        (*time > 0.0).then(|| distance / time)
+    }
+
+    fn record(&mut self, archetype: Archetype, distance: f32, time: f32) {
+        let walked = self.walked.entry(archetype).or_default();
+        walked.0 += distance;
+        walked.1 += time;
+    }
+}
+
+/// Sets the speed of navigators from their free speed, the density around them and how tired
+/// they are.
+fn update_pace(
+    mut navigators: Query<(&Transform, &Pace, &mut Navigator)>,
+    grid: Res<SpatialGrid>,
+    settings: Res<PaceSettings>,
+) {
+    let area = PI * settings.density_radius * settings.density_radius;
+    navigators
+        .par_iter_mut()
+        .for_each(|(transform, pace, mut navigator)| {
+            // The navigator itself is counted, it takes some of the room too.
+            let count = grid
+                .query_radius(transform.translation.xz(), settings.density_radius)
+                .count();
+            let density = count as f32 / area;
+            navigator.speed = pace.free_speed
+                * settings.density_factor(density)
+                * settings.fatigue_factor(pace.distance_walked);
+        });
+}
+
+/// Adds the distance walked during the tick to each navigator and to the statistics of its
+/// archetype. Only navigators with somewhere to go count as walking.
+fn record_walking(
+    mut navigators: Query<(&mut Pace, &Velocity, Option<&Archetype>)>,
+    mut stats: ResMut<WalkingStats>,
+    time: Res<Time>,
+) {
+    let delta = time.delta_seconds();
+    // Sums are in the same order on every run, unlike with a parallel iteration.
+    for (mut pace, velocity, archetype) in &mut navigators {
+        let distance = velocity.current.length() * delta;
+        pace.distance_walked += distance;
+        if velocity.preferred != Vec2::ZERO {
+            stats.record(archetype.copied().unwrap_or_default(), distance, delta);
+        }
+    }
+}
+
+/// Logs the average walking speed of each archetype when V is pressed.
+pub fn log_walking_speeds(input: Res<ButtonInput<KeyCode>>, stats: Res<WalkingStats>) {
+    if !input.just_pressed(KeyCode::KeyV) {
+        return;
+    }
+    for archetype in Archetype::ALL {
+        if let Some(speed) = stats.average_speed(archetype) {
+            info!("Average walking speed of {archetype:?}: {speed:.2} m/s");
+        }
+    }
+}
@@ -0,0 +1,375 @@
+//! Least recently used cache of paths between navmesh polygons. Navigators picking random
+//! destinations often search between the same polygons: a cached path gives the corners to go
+//! through, and is refined for the actual start and end points without running A* again. The
+//! refinement runs in the path search tasks, next to the searches for requests that missed.
+//!
+//! The cache is cleared whenever the navmesh asset changes, for example after an obstacle is
+//! placed. Paths crossing polygons that got much more or less crowded are dropped by
+//! [`update_congestion`](crate::congestion::update_congestion).
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use vleue_navigator::NavMesh;
+
+use crate::clearance::SizeClass;
+
+/// Tolerance on where a segment crosses an edge, relative to their lengths.
+const EDGE_TOLERANCE: f32 = 1e-4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct PathCacheKey {
+    /// Each size class searches on its own navmesh, with its own polygons.
+    class: SizeClass,
+    start: u32,
+    end: u32,
+    generation: u32,
+}
+
+struct CachedPath {
+    /// Waypoints between the start and the end of the path.
+    corners: Arc<[Vec3]>,
+    /// Polygons the path crosses, sorted.
+    polygons: Vec<u32>,
+    last_used: u64,
+}
+
+#[derive(Resource)]
+pub struct PathCache {
+    /// Path requests only use the cache when enabled.
+    pub enabled: bool,
+    capacity: usize,
+    /// Incremented each time the navmesh changes, so that paths searched on an older navmesh
+    /// aren't cached.
+    generation: u32,
+    entries: HashMap<PathCacheKey, CachedPath>,
+    /// Keys by the time they were last used, oldest first.
+    recency: BTreeMap<u64, PathCacheKey>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl Default for PathCache {
+    fn default() -> Self {
+        Self::new(10_000)
+    }
+}
+
+impl PathCache {
+    /// A disabled cache keeping at most `capacity` paths.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            enabled: false,
+            capacity,
+            generation: 0,
+            entries: HashMap::default(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// The same cache, used by path requests only if `enabled`.
+    pub fn with_enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Removes all paths, they were found on a navmesh that changed.
+    pub fn invalidate(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.generation = self.generation.wrapping_add(1);
+    }
+
+    /// The key of a path from `from` to `to` on the navmesh of `class`, if both are in it.
+    pub fn key(
+        &self,
+        navmesh: &NavMesh,
+        class: SizeClass,
+        from: Vec3,
+        to: Vec3,
+    ) -> Option<PathCacheKey> {
+        Some(PathCacheKey {
+            class,
+            start: polygon_at(navmesh, from)?,
+            end: polygon_at(navmesh, to)?,
+            generation: self.generation,
+        })
+    }
+
+    /// The corners of the cached path for `key`, to [`refine`] for the actual start and end.
+    pub fn get(&mut self, key: PathCacheKey) -> Option<Arc<[Vec3]>> {
+        let corners = self.entries.get(&key)?.corners.clone();
+        self.touch(key);
+        Some(corners)
+    }
+
+    /// Counts a request answered from the cache if `hit`, or one that had to be searched.
+    pub fn record(&mut self, hit: bool) {
+        if hit {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+    }
+
+    /// Caches `waypoints`, the path found for `key`, not including its start, crossing
+    /// `polygons`.
+    pub fn insert(&mut self, key: PathCacheKey, waypoints: &[Vec3], polygons: Vec<u32>) {
+        if key.generation != self.generation || self.capacity == 0 {
+            return;
+        }
+        let Some((_, corners)) = waypoints.split_last() else {
+            return;
+        };
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        if let Some(previous) = self.entries.insert(
+            key,
+            CachedPath {
+                corners: corners.into(),
+                polygons,
+                last_used: self.clock,
+            },
+        ) {
+            self.recency.remove(&previous.last_used);
+        }
+        self.recency.insert(self.clock, key);
+    }
+
+    /// Removes the paths of `class` crossing one of `polygons`.
+    pub fn evict_crossing(&mut self, class: SizeClass, polygons: &HashSet<u32>) {
+        let recency = &mut self.recency;
+        self.entries.retain(|key, cached| {
+            let crossing = key.class == class
+                && cached
+                    .polygons
+                    .iter()
+                    .any(|polygon| polygons.contains(polygon));
+            if crossing {
+                recency.remove(&cached.last_used);
+            }
+            !crossing
+        });
+    }
+
+    fn touch(&mut self, key: PathCacheKey) {
+        self.clock += 1;
+        if let Some(cached) = self.entries.get_mut(&key) {
+            self.recency.remove(&cached.last_used);
+            cached.last_used = self.clock;
+            self.recency.insert(self.clock, key);
+        }
+    }
+}
+
+/// Index of the navmesh polygon containing `point`.
+fn polygon_at(navmesh: &NavMesh, point: Vec3) -> Option<u32> {
+    let local = navmesh
+        .transform()
+        .compute_matrix()
+        .inverse()
+        .transform_point3(point);
+    let polygon = navmesh.get().get_point_location(local.xy());
+    (polygon != u32::MAX).then_some(polygon)
+}
+
+/// Whether the segment from `from` to `to` stays in the navmesh. Walks the polygons it crosses
+/// from the one `from` is in, and fails where it leaves one through an edge no other polygon
+/// shares.
+pub(crate) fn visible(navmesh: &NavMesh, from: Vec3, to: Vec3) -> bool {
+    walk(navmesh, from, to, |_| {})
+}
+
+/// The polygons the path from `from` through `waypoints` crosses, sorted. `None` if a part of it
+/// leaves the navmesh.
+pub(crate) fn crossed_polygons(
+    navmesh: &NavMesh,
+    from: Vec3,
+    waypoints: &[Vec3],
+) -> Option<Vec<u32>> {
+    let mut polygons = Vec::new();
+    let starts = std::iter::once(from).chain(waypoints.iter().copied());
+    for (start, end) in starts.zip(waypoints) {
+        if !walk(navmesh, start, *end, |polygon| polygons.push(polygon)) {
+            return None;
+        }
+    }
+    polygons.sort_unstable();
+    polygons.dedup();
+    Some(polygons)
+}
+
+/// Walks the segment from `from` to `to` through the polygons it crosses, calling `enter` with
+/// each of them. `false` if it leaves the navmesh.
+fn walk(navmesh: &NavMesh, from: Vec3, to: Vec3, mut enter: impl FnMut(u32)) -> bool {
+    let to_navmesh = navmesh.transform().compute_matrix().inverse();
+    let start = to_navmesh.transform_point3(from).xy();
+    let direction = to_navmesh.transform_point3(to).xy() - start;
+    let mesh = navmesh.get();
+    // Navmeshes built from obstacles have a single layer.
+    let layer = &mesh.layers[0];
+    let coords = |vertex: u32| layer.vertices[vertex as usize].coords;
+    let mut polygon = mesh.get_point_location(start);
+    // Each polygon is crossed at most once.
+    for _ in 0..layer.polygons.len() {
+        let Some(current) = layer.polygons.get(polygon as usize) else {
+            return false;
+        };
+        enter(polygon);
+        let center = current.vertices.iter().map(|v| coords(*v)).sum::<Vec2>()
+            / current.vertices.len().max(1) as f32;
+        let next = current.vertices.iter().cycle().skip(1);
+        // The edge the segment leaves the polygon through, as a fraction of the segment.
+        let exit = current
+            .vertices
+            .iter()
+            .zip(next)
+            .filter_map(|(&a, &b)| {
+                let edge = coords(b) - coords(a);
+                let outward = edge.perp_dot(direction);
+                // Only edges crossed going away from the inside of the polygon.
+                if outward == 0.0 || outward.signum() == edge.perp_dot(center - coords(a)).signum()
+                {
+                    return None;
+                }
+                let crossing = -outward;
+                let along_segment = (coords(a) - start).perp_dot(edge) / crossing;
+                let along_edge = (coords(a) - start).perp_dot(direction) / crossing;
+                (along_segment >= -EDGE_TOLERANCE
+                    && (-EDGE_TOLERANCE..=1.0 + EDGE_TOLERANCE).contains(&along_edge))
+                .then_some((along_segment, a, b))
+            })
+            .max_by(|(x, ..), (y, ..)| x.total_cmp(y));
+        let Some((along_segment, a, b)) = exit else {
+            // Not moving.
+            return true;
+        };
+        if along_segment >= 1.0 - EDGE_TOLERANCE {
+            // The segment ends in this polygon.
+            return true;
+        }
+        let shared = layer.vertices[a as usize].polygons.iter().find(|&&other| {
+            other != polygon
+                && other != u32::MAX
+                && layer.vertices[b as usize].polygons.contains(&other)
+        });
+        match shared {
+            Some(&other) => polygon = other,
+            None => return false,
+        }
+    }
+    false
+}
+
+/// Pulls the path from `from` through `corners` to `to` tight, skipping the corners that aren't
+/// needed from the new start and end points. The corners in between were already tight, only the
+/// ends are checked. `None` if a part of it leaves the navmesh.
+pub fn refine(navmesh: &NavMesh, from: Vec3, corners: &[Vec3], to: Vec3) -> Option<Vec<Vec3>> {
+    let points = corners
+        .iter()
+        .copied()
+        .chain(std::iter::once(to))
+        .collect::<Vec<_>>();
+    // Skip the first corners while the one after is visible from the new start.
+    let mut first = 0;
+    while first + 1 < points.len() && visible(navmesh, from, points[first + 1]) {
+        first += 1;
+    }
+    if first == 0 && !visible(navmesh, from, points[0]) {
+        return None;
+    }
+    // Then the last corners while the new end is visible from the one before.
+    let mut last = points.len() - 1;
+    while last > first + 1 && visible(navmesh, points[last - 2], to) {
+        last -= 1;
+    }
+    if last == points.len() - 1 && last > first && !visible(navmesh, points[last - 1], to) {
+        return None;
+    }
+    let mut waypoints = points[first..last].to_vec();
+    waypoints.push(to);
+    Some(waypoints)
+}
+
+pub fn invalidate_path_cache(
+    mut events: EventReader<AssetEvent<NavMesh>>,
+    mut cache: ResMut<PathCache>,
+) {
+    if events.read().any(|event| {
+        matches!(
+            event,
+            AssetEvent::Added { .. } | AssetEvent::Modified { .. } | AssetEvent::Removed { .. }
+        )
+    }) {
+        cache.invalidate();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(cache: &PathCache, start: u32) -> PathCacheKey {
+        PathCacheKey {
+            class: SizeClass::Small,
+            start,
+            end: start + 100,
+            generation: cache.generation,
+        }
+    }
+
+    fn insert(cache: &mut PathCache, key: PathCacheKey) {
+        cache.insert(key, &[Vec3::X, Vec3::Z], vec![key.start, key.end]);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_path() {
+        let mut cache = PathCache::new(2);
+        let (a, b, c) = (key(&cache, 1), key(&cache, 2), key(&cache, 3));
+        insert(&mut cache, a);
+        insert(&mut cache, b);
+        // Using `a` makes `b` the oldest.
+        assert_eq!(cache.get(a).as_deref(), Some(&[Vec3::X][..]));
+        insert(&mut cache, c);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(a).is_some());
+        assert!(cache.get(b).is_none());
+        assert!(cache.get(c).is_some());
+    }
+
+    #[test]
+    fn ignores_paths_from_an_older_navmesh() {
+        let mut cache = PathCache::new(2);
+        let stale = key(&cache, 1);
+        cache.invalidate();
+        insert(&mut cache, stale);
+        assert!(cache.is_empty());
+    }
+}
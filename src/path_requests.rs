@@ -0,0 +1,412 @@
+//! Path searches run in the background on the [`AsyncComputeTaskPool`], a limited number each
+//! frame, so that thousands of navigators asking for a path at once (spawning with P, dropping
+//! all paths with K) don't stall a frame.
+//!
+//! Navigators submit a [`PathRequest`] and are marked [`WaitingForPath`] until a [`Path`] is
+//! inserted. If no path is found the marker is removed and they'll pick another target. A
+//! [`ChoiceRequest`] searches the paths to several destinations, and keeps the cheapest one.
+//!
+//! When the [`PathCache`] is enabled, requests between polygons that were already searched take
+//! the cached corners along to their task, which only refines them for the actual start and end.
+//! Requests to a destination with a [`FlowField`](crate::flow_field::FlowField) are answered when
+//! dispatched, and don't count towards a task.
+//!
+//! Paths searched on a navmesh that was rebuilt in the meantime are dropped, and their request
+//! searched again on the new one.
+
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use bevy::{
+    prelude::*,
+    tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task},
+    utils::Instant,
+};
+
+use crate::{
+    agent3d::{Path, SimulationStats},
+    clearance::{FoundPath, PathNavMeshes, SearchNavMeshes, SizeClass},
+    path_cache::{crossed_polygons, refine, PathCache, PathCacheKey},
+    stall::VisitingStall,
+};
+
+/// Limits on the path searches started each frame.
+#[derive(Resource, Clone, Debug)]
+pub struct PathRequestBudget {
+    /// Maximum number of requests started each frame.
+    pub requests_per_frame: usize,
+    /// Number of requests handled by a single task. Tasks run in parallel.
+    pub batch_size: usize,
+    /// Time a task can spend searching. Requests it didn't get to are queued again.
+    pub time_per_batch: Duration,
+    /// Ignore `time_per_batch` and wait for the searches started during a tick on the next one,
+    /// so that paths arrive at the same tick on every run.
+    pub deterministic: bool,
+}
+
+impl Default for PathRequestBudget {
+    fn default() -> Self {
+        Self {
+            requests_per_frame: 500,
+            batch_size: 50,
+            time_per_batch: Duration::from_millis(4),
+            deterministic: false,
+        }
+    }
+}
+
+/// Marks a navigator whose path is being searched.
+#[derive(Component)]
+pub struct WaitingForPath;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PathRequest {
+    pub entity: Entity,
+    pub from: Vec3,
+    pub to: Vec3,
+    /// The path is searched on the navmesh of this class.
+    pub size_class: SizeClass,
+}
+
+/// A request for the path to the cheapest of several destinations, for navigators that choose
+/// where to go by how far it is to walk.
+#[derive(Clone, Debug)]
+pub struct ChoiceRequest {
+    pub entity: Entity,
+    pub from: Vec3,
+    pub size_class: SizeClass,
+    pub options: Vec<PathChoice>,
+    /// Cost of each meter of path.
+    pub distance_weight: f32,
+}
+
+/// One of the destinations of a [`ChoiceRequest`].
+#[derive(Clone, Copy, Debug)]
+pub struct PathChoice {
+    pub to: Vec3,
+    /// Cost of the destination itself, added to the one of the path to it.
+    pub cost: f32,
+    /// Stall the navigator visits if this destination is chosen.
+    pub stall: Option<Entity>,
+}
+
+impl ChoiceRequest {
+    /// The path to the cheapest reachable destination, and the destination.
+    fn search(&self, navmeshes: &SearchNavMeshes) -> Option<(FoundPath, PathChoice)> {
+        self.options
+            .iter()
+            .filter_map(|option| {
+                let path = navmeshes.path(self.size_class, self.from, option.to)?;
+                let cost = self.distance_weight * path.length + option.cost;
+                Some((cost, path, *option))
+            })
+            // The first of equally cheap options is kept.
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, path, option)| (path, option))
+    }
+}
+
+/// A request waiting in the queue.
+#[derive(Clone, Debug)]
+enum QueuedRequest {
+    Path(PathRequest),
+    Choice(ChoiceRequest),
+}
+
+impl QueuedRequest {
+    fn entity(&self) -> Entity {
+        match self {
+            QueuedRequest::Path(request) => request.entity,
+            QueuedRequest::Choice(request) => request.entity,
+        }
+    }
+
+    fn size_class(&self) -> SizeClass {
+        match self {
+            QueuedRequest::Path(request) => request.size_class,
+            QueuedRequest::Choice(request) => request.size_class,
+        }
+    }
+}
+
+/// A request handed to a task.
+enum Search {
+    Path {
+        request: PathRequest,
+        /// Key to look the path up with, if the cache is enabled.
+        key: Option<PathCacheKey>,
+        /// Corners of the cached path between the same polygons.
+        cached: Option<Arc<[Vec3]>>,
+    },
+    Choice(ChoiceRequest),
+}
+
+impl Search {
+    fn run(self, navmeshes: &SearchNavMeshes) -> SearchedPath {
+        match self {
+            Search::Path {
+                request,
+                key,
+                cached,
+            } => {
+                let refined = cached.and_then(|corners| {
+                    let navmesh = navmeshes.get(request.size_class);
+                    refine(navmesh, request.from, &corners, request.to)
+                });
+                let hit = refined.is_some();
+                let path = refined
+                    .map(|waypoints| FoundPath::through(request.from, waypoints))
+                    .or_else(|| navmeshes.path(request.size_class, request.from, request.to));
+                // Paths along a flow field only hold its next corner.
+                let polygons = path
+                    .as_ref()
+                    .filter(|path| key.is_some() && !hit && path.flow_field.is_none())
+                    .and_then(|path| {
+                        let navmesh = navmeshes.get(request.size_class);
+                        crossed_polygons(navmesh, request.from, &path.path)
+                    });
+                SearchedPath {
+                    request: QueuedRequest::Path(request),
+                    key,
+                    path,
+                    hit,
+                    polygons,
+                    stall: None,
+                }
+            }
+            Search::Choice(request) => {
+                let chosen = request.search(navmeshes);
+                SearchedPath {
+                    request: QueuedRequest::Choice(request),
+                    key: None,
+                    stall: chosen.as_ref().and_then(|(_, option)| option.stall),
+                    path: chosen.map(|(path, _)| path),
+                    hit: false,
+                    polygons: None,
+                }
+            }
+        }
+    }
+
+    fn into_request(self) -> QueuedRequest {
+        match self {
+            Search::Path { request, .. } => QueuedRequest::Path(request),
+            Search::Choice(request) => QueuedRequest::Choice(request),
+        }
+    }
+}
+
+/// The answer to a request.
+struct SearchedPath {
+    request: QueuedRequest,
+    /// Key the path was looked up with, if the cache is enabled.
+    key: Option<PathCacheKey>,
+    path: Option<FoundPath>,
+    /// Whether the path came from the cache.
+    hit: bool,
+    /// Polygons the path crosses, if it should be cached.
+    polygons: Option<Vec<u32>>,
+    /// Stall chosen by a [`ChoiceRequest`].
+    stall: Option<Entity>,
+}
+
+struct BatchResult {
+    /// The navmeshes the paths were searched on.
+    navmeshes: SearchNavMeshes,
+    paths: Vec<SearchedPath>,
+    /// Requests that didn't fit in the time budget.
+    remaining: Vec<QueuedRequest>,
+}
+
+/// Queue of path requests waiting to be searched, and the searches in progress.
+#[derive(Resource, Default)]
+pub struct PathRequests {
+    queue: VecDeque<QueuedRequest>,
+    tasks: Vec<Task<BatchResult>>,
+}
+
+impl PathRequests {
+    /// Queues a request. The navigator should be marked [`WaitingForPath`].
+    pub fn push(&mut self, request: PathRequest) {
+        self.queue.push_back(QueuedRequest::Path(request));
+    }
+
+    /// Queues a request between several destinations. The navigator should be marked
+    /// [`WaitingForPath`], and is marked [`VisitingStall`] if it chose a stall.
+    pub fn push_choice(&mut self, request: ChoiceRequest) {
+        self.queue.push_back(QueuedRequest::Choice(request));
+    }
+
+    /// Number of requests that haven't started yet.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Number of batches being searched.
+    pub fn in_progress(&self) -> usize {
+        self.tasks.len()
+    }
+}
+
+pub fn dispatch_path_requests(
+    mut commands: Commands,
+    mut requests: ResMut<PathRequests>,
+    mut cache: ResMut<PathCache>,
+    mut stats: ResMut<SimulationStats>,
+    budget: Res<PathRequestBudget>,
+    navmeshes: PathNavMeshes,
+) {
+    if requests.queue.is_empty() {
+        return;
+    }
+    let Some(navmeshes) = navmeshes.get() else {
+        return;
+    };
+
+    let task_pool = AsyncComputeTaskPool::get();
+    let count = budget.requests_per_frame.min(requests.queue.len());
+    let mut started = Vec::with_capacity(count);
+    for request in requests.queue.drain(..count).collect::<Vec<_>>() {
+        let request = match request {
+            QueuedRequest::Path(request) => request,
+            QueuedRequest::Choice(request) => {
+                started.push(Search::Choice(request));
+                continue;
+            }
+        };
+        // Popular destinations have a flow field, walking down it needs no search.
+        let flow_path = navmeshes
+            .flow_field(request.size_class, request.to)
+            .and_then(|field| field.path(request.from, request.to));
+        if let Some(path) = flow_path {
+            apply_path(
+                &mut commands,
+                &mut stats,
+                request.entity,
+                Path::from_found(&path),
+                path.length,
+            );
+            continue;
+        }
+        let navmesh = navmeshes.get(request.size_class);
+        let key = if cache.enabled {
+            cache.key(navmesh, request.size_class, request.from, request.to)
+        } else {
+            None
+        };
+        let cached = key.and_then(|key| cache.get(key));
+        started.push(Search::Path {
+            request,
+            key,
+            cached,
+        });
+    }
+    while !started.is_empty() {
+        let batch = started
+            .drain(..budget.batch_size.max(1).min(started.len()))
+            .collect::<Vec<_>>();
+        let navmeshes = navmeshes.clone();
+        let time_per_batch = (!budget.deterministic).then_some(budget.time_per_batch);
+        requests.tasks.push(task_pool.spawn(async move {
+            let start = Instant::now();
+            let mut batch = batch.into_iter();
+            let mut paths = Vec::new();
+            for search in batch.by_ref() {
+                paths.push(search.run(&navmeshes));
+                if time_per_batch.is_some_and(|limit| start.elapsed() > limit) {
+                    break;
+                }
+            }
+            BatchResult {
+                navmeshes,
+                paths,
+                remaining: batch.map(Search::into_request).collect(),
+            }
+        }));
+    }
+}
+
+pub fn apply_path_results(
+    mut commands: Commands,
+    mut requests: ResMut<PathRequests>,
+    mut cache: ResMut<PathCache>,
+    mut stats: ResMut<SimulationStats>,
+    budget: Res<PathRequestBudget>,
+    navmeshes: PathNavMeshes,
+) {
+    let current = navmeshes.get();
+    let PathRequests { queue, tasks } = &mut *requests;
+    tasks.retain_mut(|task| {
+        let result = if budget.deterministic {
+            block_on(task)
+        } else {
+            let Some(result) = block_on(future::poll_once(task)) else {
+                return true;
+            };
+            result
+        };
+        let mut outdated = Vec::new();
+        for searched in result.paths {
+            let entity = searched.request.entity();
+            let class = searched.request.size_class();
+            if !current
+                .as_ref()
+                .is_some_and(|current| current.same_navmesh(&result.navmeshes, class))
+            {
+                outdated.push(searched.request);
+                continue;
+            }
+            if searched.key.is_some() {
+                cache.record(searched.hit);
+            }
+            match searched.path {
+                Some(path) => {
+                    if let (Some(key), Some(polygons)) = (searched.key, searched.polygons) {
+                        cache.insert(key, &path.path, polygons);
+                    }
+                    apply_path(
+                        &mut commands,
+                        &mut stats,
+                        entity,
+                        Path::from_found(&path),
+                        path.length,
+                    );
+                    if let (Some(stall), Some(mut entity)) =
+                        (searched.stall, commands.get_entity(entity))
+                    {
+                        entity.insert(VisitingStall(stall));
+                    }
+                }
+                None => {
+                    if let Some(mut entity) = commands.get_entity(entity) {
+                        entity.remove::<WaitingForPath>();
+                    }
+                }
+            }
+        }
+        // Keep their place at the front of the queue.
+        for request in outdated.into_iter().chain(result.remaining).rev() {
+            queue.push_front(request);
+        }
+        false
+    });
+}
+
+/// Gives `new_path` to a navigator that was waiting for it.
+fn apply_path(
+    commands: &mut Commands,
+    stats: &mut SimulationStats,
+    entity: Entity,
+    new_path: Option<Path>,
+    length: f32,
+) {
+    let Some(mut entity) = commands.get_entity(entity) else {
+        // Despawned while waiting.
+        return;
+    };
+    entity.remove::<WaitingForPath>();
+    if let Some(new_path) = new_path {
+        entity.insert(new_path);
+        stats.paths_found += 1;
+        stats.total_path_length += length;
+    }
+}
@@ -0,0 +1,255 @@
+//! Queues in front of stalls. A queue is a line of slots starting at the access point of a stall
+//! and extending away from it over the navmesh. Navigators arriving at a stall take the first
+//! free slot, move forward as the navigator at the head is served, and give up once they've
+//! waited longer than their patience or the stall closes.
+
+use std::f32::consts::{FRAC_PI_2, FRAC_PI_4};
+
+use bevy::prelude::*;
+use vleue_navigator::NavMesh;
+
+use crate::{
+    agent3d::{Path, SimulationStats},
+    clock::TimeOfDay,
+    path_requests::WaitingForPath,
+    stall::{Dwelling, Stall, VisitingStall},
+};
+
+/// How close to the queue a navigator must stop to join it.
+const JOIN_DISTANCE: f32 = 2.0;
+
+#[derive(Resource, Clone, Debug)]
+pub struct QueueSettings {
+    /// Distance between two slots of a queue.
+    pub spacing: f32,
+    /// Maximum number of navigators in a queue, including the one being served.
+    pub max_length: usize,
+    /// Time a navigator waits in a queue before giving up, in seconds.
+    pub patience: f32,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            spacing: 1.5,
+            max_length: 8,
+            patience: 60.0,
+        }
+    }
+}
+
+/// The queue of a stall.
+#[derive(Component, Default, Debug)]
+pub struct StallQueue {
+    /// Where navigators stand, the first one being the access point of the stall. Empty if the
+    /// stall can't be reached.
+    slots: Vec<Vec3>,
+    /// Navigators in the queue, the first one being served.
+    members: Vec<Entity>,
+}
+
+impl StallQueue {
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.members.len() >= self.slots.len()
+    }
+
+    /// Where a navigator arriving now would stand, `None` if the stall can't be reached.
+    pub fn entry_point(&self) -> Option<Vec3> {
+        let last = self.slots.len().checked_sub(1)?;
+        Some(self.slots[self.members.len().min(last)])
+    }
+
+    fn is_near(&self, position: Vec3) -> bool {
+        self.slots
+            .iter()
+            .any(|slot| slot.xz().distance(position.xz()) <= JOIN_DISTANCE)
+    }
+
+    /// Navigators in the queue, the first one being served.
+    pub fn members(&self) -> &[Entity] {
+        &self.members
+    }
+
+    /// A queue holding `members` in this order, placed in front of its stall when the navmesh is
+    /// built.
+    pub(crate) fn with_members(members: Vec<Entity>) -> Self {
+        Self {
+            slots: Vec::new(),
+            members,
+        }
+    }
+
+    /// Replaces the slots of the queue. Navigators that don't fit anymore are kept in the list
+    /// and sent away by [`update_queues`].
+    pub(crate) fn set_slots(&mut self, slots: Vec<Vec3>) {
+        self.slots = slots;
+    }
+}
+
+/// A navigator waiting in the queue of a stall.
+#[derive(Component)]
+pub struct Queueing {
+    pub stall: Entity,
+    /// Slot the navigator was sent to.
+    slot: Option<Vec3>,
+    /// Time left before giving up, in seconds.
+    patience: f32,
+}
+
+impl Queueing {
+    /// Waiting in the queue of `stall` for at most `patience` seconds, not sent to a slot yet.
+    pub(crate) fn new(stall: Entity, patience: f32) -> Self {
+        Self {
+            stall,
+            slot: None,
+            patience,
+        }
+    }
+
+    /// Time left before giving up, in seconds.
+    pub fn patience(&self) -> f32 {
+        self.patience
+    }
+}
+
+/// Slots of a queue starting at `access_point` and going in `outward` direction, turning if an
+/// obstacle is in the way. The direction giving the longest queue is kept.
+pub(crate) fn queue_slots(
+    navmesh: &NavMesh,
+    access_point: Vec3,
+    outward: Vec2,
+    settings: &QueueSettings,
+) -> Vec<Vec3> {
+    [0.0, FRAC_PI_4, -FRAC_PI_4, FRAC_PI_2, -FRAC_PI_2]
+        .into_iter()
+        .map(|angle| {
+            let direction = Vec2::from_angle(angle).rotate(outward) * settings.spacing;
+            let mut slots = vec![access_point];
+            while slots.len() < settings.max_length {
+                let previous = slots[slots.len() - 1];
+                let slot = previous + Vec3::new(direction.x, 0.0, direction.y);
+                if !navmesh.transformed_is_in_mesh(slot)
+                    || !navmesh.transformed_is_in_mesh(previous.lerp(slot, 0.5))
+                {
+                    break;
+                }
+                slots.push(slot);
+            }
+            slots
+        })
+        .fold(Vec::new(), |longest, slots| {
+            if slots.len() > longest.len() {
+                slots
+            } else {
+                longest
+            }
+        })
+}
+
+/// Adds the navigators that stopped next to the stall they were walking to at the end of its
+/// queue. The others lost their path on the way, or found the queue full or the stall closed, and
+/// will pick a new destination.
+pub(crate) fn join_queues(
+    mut commands: Commands,
+    navigators: Query<
+        (Entity, &Transform, &VisitingStall),
+        (Without<Path>, Without<WaitingForPath>),
+    >,
+    mut stalls: Query<(&Stall, &mut StallQueue)>,
+    settings: Res<QueueSettings>,
+    time_of_day: Res<TimeOfDay>,
+) {
+    let mut arrived = navigators.iter().collect::<Vec<_>>();
+    // Navigators arriving on the same tick line up in the same order on every run.
+    arrived.sort_unstable_by_key(|(entity, ..)| *entity);
+    for (entity, transform, visiting) in arrived {
+        let mut entity_commands = commands.entity(entity);
+        entity_commands.remove::<VisitingStall>();
+        let Ok((stall, mut queue)) = stalls.get_mut(visiting.0) else {
+            continue;
+        };
+        if queue.is_full()
+            || !queue.is_near(transform.translation)
+            || !stall.opening_hours.is_open(time_of_day.hours())
+        {
+            continue;
+        }
+        queue.members.push(entity);
+        entity_commands.insert(Queueing::new(visiting.0, settings.patience));
+    }
+}
+
+/// Removes the navigators that waited too long from their queue.
+pub(crate) fn lose_patience(
+    mut commands: Commands,
+    mut navigators: Query<(Entity, &mut Queueing), Without<Dwelling>>,
+    mut stats: ResMut<SimulationStats>,
+    time: Res<Time>,
+) {
+    for (entity, mut queueing) in &mut navigators {
+        queueing.patience -= time.delta_seconds();
+        if queueing.patience <= 0.0 {
+            commands.entity(entity).remove::<(Queueing, Path)>();
+            stats.gave_up += 1;
+        }
+    }
+}
+
+/// Moves the navigators forward in their queue after others left, and starts serving the one at
+/// the head once it reached the access point. Closed stalls finish serving but send the rest of
+/// their queue away.
+pub(crate) fn update_queues(
+    mut commands: Commands,
+    mut stalls: Query<(Entity, &Stall, &mut StallQueue)>,
+    mut navigators: Query<(&mut Queueing, Has<Path>, Has<Dwelling>)>,
+    time_of_day: Res<TimeOfDay>,
+) {
+    for (stall_entity, stall, mut queue) in &mut stalls {
+        let StallQueue { slots, members } = &mut *queue;
+        members.retain(|member| {
+            navigators
+                .get(*member)
+                .is_ok_and(|(queueing, ..)| queueing.stall == stall_entity)
+        });
+        if !stall.opening_hours.is_open(time_of_day.hours()) {
+            members.retain(|member| {
+                let served = navigators
+                    .get(*member)
+                    .is_ok_and(|(_, _, dwelling)| dwelling);
+                if !served {
+                    commands.entity(*member).remove::<(Queueing, Path)>();
+                }
+                served
+            });
+        }
+        if members.len() > slots.len() {
+            for member in members.drain(slots.len()..) {
+                commands.entity(member).remove::<(Queueing, Path)>();
+            }
+        }
+
+        for (index, (&member, &slot)) in members.iter().zip(slots.iter()).enumerate() {
+            let Ok((mut queueing, has_path, dwelling)) = navigators.get_mut(member) else {
+                continue;
+            };
+            if queueing.slot != Some(slot) {
+                queueing.slot = Some(slot);
+                if let Some(path) = Path::from_waypoints(&[slot]) {
+                    commands.entity(member).insert(path);
+                }
+            } else if index == 0 && !has_path && !dwelling {
+                commands
+                    .entity(member)
+                    .insert(Dwelling::new(stall_entity, stall.dwell_time));
+            }
+        }
+    }
+}
@@ -0,0 +1,439 @@
+//! Saves the obstacle layout and the crowd state to a RON file, and loads it back so the
+//! simulation resumes where it stopped.
+//!
+//! F5 saves to [`DEFAULT_SAVE_PATH`] and F9 loads it. Other files can be used by sending
+//! [`SaveSimulation`] and [`LoadSimulation`] events.
+
+use std::{error::Error, path::PathBuf};
+
+use bevy::{prelude::*, utils::EntityHashMap};
+use serde::{Deserialize, Serialize};
+use vleue_navigator::prelude::{NavMeshUpdateMode, PrimitiveObstacle};
+
+use crate::{
+    agent3d::{spawn_navigator, Navigator, Path},
+    archetype::{Archetype, Archetypes},
+    clearance::ClassNavMesh,
+    clock::TimeOfDay,
+    economy::{Demand, Ledger, Sale, Stock},
+    gates::{self, Gate},
+    group::{Follower, GroupLeader},
+    pace::Pace,
+    queue::{Queueing, StallQueue},
+    scenario::{ObstacleDescription, ObstacleShape},
+    shopping::{Leaving, Shopper},
+    spawner::ObstacleVisual,
+    stall::{Dwelling, Stall, StallDescription, StallId, VisitingStall},
+    ChangedMesh, EntityRng, MapSize, Materials, MyCapsule, SimulationRng,
+};
+
+pub const DEFAULT_SAVE_PATH: &str = "market.save.ron";
+
+pub struct SavePlugin;
+
+impl Plugin for SavePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<SaveSimulation>()
+            .add_event::<LoadSimulation>()
+            .add_systems(
+                Update,
+                (save_load_keys, save_simulation, load_simulation).chain(),
+            );
+    }
+}
+
+/// Writes the current simulation to `path`.
+#[derive(Event)]
+pub struct SaveSimulation {
+    pub path: PathBuf,
+}
+
+/// Replaces the current obstacles and agents with the ones saved in `path`.
+#[derive(Event)]
+pub struct LoadSimulation {
+    pub path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedSimulation {
+    pub map_size: MapSize,
+    /// State of the [`SimulationRng`].
+    pub rng_state: u64,
+    #[serde(default)]
+    pub time_of_day: TimeOfDay,
+    pub obstacles: Vec<ObstacleDescription>,
+    #[serde(default)]
+    pub stalls: Vec<StallDescription>,
+    #[serde(default = "Gate::default_gates")]
+    pub gates: Vec<Gate>,
+    pub navigators: Vec<SavedNavigator>,
+    /// Indices in the saved navigators of those in the queue of each saved stall, in the order
+    /// they queue in.
+    #[serde(default)]
+    pub queues: Vec<Vec<usize>>,
+    /// Every sale made so far, by the id of the stall.
+    #[serde(default)]
+    pub ledger: Vec<Sale>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedNavigator {
+    pub transform: Transform,
+    pub navigator: Navigator,
+    /// Remaining waypoints, if the navigator was walking somewhere.
+    pub path: Option<Path>,
+    /// State of its [`EntityRng`]. A new one is forked from the [`SimulationRng`] if missing.
+    #[serde(default)]
+    pub rng_state: Option<u64>,
+    /// What it still has to buy, if it came to shop.
+    #[serde(default)]
+    pub shopper: Option<Shopper>,
+    /// Its kind, from which its size is restored.
+    #[serde(default)]
+    pub archetype: Option<Archetype>,
+    /// Index in the saved navigators of the leader of its group, if it follows one.
+    #[serde(default)]
+    pub leader: Option<usize>,
+    /// Its free speed and how far it walked. Navigators without one keep the speed they were
+    /// saved with.
+    #[serde(default)]
+    pub pace: Option<Pace>,
+    /// Index in the saved stalls of the stall it was walking to.
+    #[serde(default)]
+    pub visiting: Option<usize>,
+    /// The stall it was being served at, if any.
+    #[serde(default)]
+    pub dwelling: Option<SavedDwelling>,
+    /// Whether it was walking to an exit.
+    #[serde(default)]
+    pub leaving: bool,
+    /// The queue it was waiting in, if any.
+    #[serde(default)]
+    pub queueing: Option<SavedQueueing>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedQueueing {
+    /// Index in the saved stalls.
+    pub stall: usize,
+    /// Time left before giving up, in seconds.
+    pub patience: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedDwelling {
+    /// Index in the saved stalls.
+    pub stall: usize,
+    /// Time left before being served, in seconds.
+    pub remaining: f32,
+}
+
+fn save_load_keys(
+    input: Res<ButtonInput<KeyCode>>,
+    mut save: EventWriter<SaveSimulation>,
+    mut load: EventWriter<LoadSimulation>,
+) {
+    if input.just_pressed(KeyCode::F5) {
+        save.send(SaveSimulation {
+            path: DEFAULT_SAVE_PATH.into(),
+        });
+    }
+    if input.just_pressed(KeyCode::F9) {
+        load.send(LoadSimulation {
+            path: DEFAULT_SAVE_PATH.into(),
+        });
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn save_simulation(
+    mut events: EventReader<SaveSimulation>,
+    map_size: Res<MapSize>,
+    rng: Res<SimulationRng>,
+    time_of_day: Res<TimeOfDay>,
+    ledger: Res<Ledger>,
+    obstacles: Query<(&PrimitiveObstacle, &Transform), Without<Stall>>,
+    stalls: Query<(
+        Entity,
+        &Stall,
+        Option<&StallId>,
+        &Transform,
+        &Stock,
+        &Demand,
+        &StallQueue,
+    )>,
+    gates: Query<&Gate>,
+    navigators: Query<(
+        Entity,
+        &Transform,
+        &Navigator,
+        Option<&Path>,
+        Option<&EntityRng>,
+        Option<&Shopper>,
+        Option<&Archetype>,
+        Option<&Follower>,
+        Option<&Pace>,
+        Option<&VisitingStall>,
+        Option<&Dwelling>,
+        Has<Leaving>,
+        Option<&Queueing>,
+    )>,
+) {
+    for event in events.read() {
+        let mut saved = SavedSimulation {
+            map_size: *map_size,
+            rng_state: rng.0.get_seed(),
+            time_of_day: time_of_day.clone(),
+            obstacles: Vec::new(),
+            stalls: stalls
+                .iter()
+                .map(|(_, stall, id, transform, stock, demand, _)| {
+                    StallDescription::from_stall(stall, id.copied(), transform, stock, demand)
+                })
+                .collect(),
+            gates: gates.iter().copied().collect(),
+            navigators: Vec::new(),
+            queues: Vec::new(),
+            ledger: ledger.sales().to_vec(),
+        };
+        for (obstacle, transform) in &obstacles {
+            let Some(shape) = ObstacleShape::from_primitive(obstacle) else {
+                warn!("Skipping an obstacle with a shape that can't be saved");
+                continue;
+            };
+            if !ObstacleDescription::fits(transform) {
+                warn!("Saving an obstacle without its scale and tilt");
+            }
+            saved
+                .obstacles
+                .push(ObstacleDescription::from_transform(shape, transform));
+        }
+        let indices = navigators
+            .iter()
+            .enumerate()
+            .map(|(i, (entity, ..))| (entity, i))
+            .collect::<EntityHashMap<_, _>>();
+        let stall_indices = stalls
+            .iter()
+            .enumerate()
+            .map(|(i, (entity, ..))| (entity, i))
+            .collect::<EntityHashMap<_, _>>();
+        for (
+            _,
+            transform,
+            navigator,
+            path,
+            entity_rng,
+            shopper,
+            archetype,
+            follower,
+            pace,
+            visiting,
+            dwelling,
+            leaving,
+            queueing,
+        ) in &navigators
+        {
+            saved.navigators.push(SavedNavigator {
+                transform: *transform,
+                navigator: navigator.clone(),
+                path: path.cloned(),
+                rng_state: entity_rng.map(|entity_rng| entity_rng.0.get_seed()),
+                shopper: shopper.cloned(),
+                archetype: archetype.copied(),
+                leader: follower.and_then(|follower| indices.get(&follower.leader).copied()),
+                pace: pace.cloned(),
+                visiting: visiting.and_then(|visiting| stall_indices.get(&visiting.0).copied()),
+                dwelling: dwelling.and_then(|dwelling| {
+                    Some(SavedDwelling {
+                        stall: *stall_indices.get(&dwelling.stall)?,
+                        remaining: dwelling.remaining(),
+                    })
+                }),
+                leaving,
+                queueing: queueing.and_then(|queueing| {
+                    Some(SavedQueueing {
+                        stall: *stall_indices.get(&queueing.stall)?,
+                        patience: queueing.patience(),
+                    })
+                }),
+            });
+        }
+        saved.queues = stalls
+            .iter()
+            .map(|(.., queue)| {
+                queue
+                    .members()
+                    .iter()
+                    .filter_map(|member| indices.get(member).copied())
+                    .collect()
+            })
+            .collect();
+
+        match write_save(&event.path, &saved) {
+            Ok(()) => info!(
+                "Saved {} obstacles, {} stalls and {} units to {}",
+                saved.obstacles.len(),
+                saved.stalls.len(),
+                saved.navigators.len(),
+                event.path.display()
+            ),
+            Err(err) => error!("Could not save to {}: {err}", event.path.display()),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn load_simulation(
+    mut commands: Commands,
+    mut events: EventReader<LoadSimulation>,
+    mut map_size: ResMut<MapSize>,
+    mut rng: ResMut<SimulationRng>,
+    mut ledger: ResMut<Ledger>,
+    mut time_of_day: ResMut<TimeOfDay>,
+    archetypes: Res<Archetypes>,
+    obstacles: Query<Entity, Or<(With<PrimitiveObstacle>, With<ObstacleVisual>)>>,
+    navigators: Query<Entity, With<Navigator>>,
+    gates: Query<Entity, With<Gate>>,
+    mut navmesh_update: Query<&mut NavMeshUpdateMode, Without<ClassNavMesh>>,
+    materials: Option<Res<Materials>>,
+    capsule: Option<Res<MyCapsule>>,
+    changed_mesh: Option<ResMut<ChangedMesh>>,
+) {
+    // Only the last load matters, it replaces everything.
+    let Some(event) = events.read().last() else {
+        return;
+    };
+    let saved = match read_save(&event.path) {
+        Ok(saved) => saved,
+        Err(err) => {
+            error!("Could not load {}: {err}", event.path.display());
+            return;
+        }
+    };
+
+    for entity in obstacles.iter().chain(&navigators).chain(&gates) {
+        commands.entity(entity).despawn_recursive();
+    }
+    *ledger = Ledger::with_sales(saved.ledger.clone());
+
+    // Only touch the map size when it differs, changing it rebuilds the ground and navmesh.
+    if *map_size != saved.map_size {
+        *map_size = saved.map_size;
+    }
+    rng.0.seed(saved.rng_state);
+    *time_of_day = saved.time_of_day.clone();
+
+    for obstacle in &saved.obstacles {
+        commands.spawn(obstacle.bundle());
+    }
+    let stalls = saved
+        .stalls
+        .iter()
+        .map(|stall| {
+            let mut entity = commands.spawn(stall.bundle());
+            if let Some(id) = stall.id {
+                entity.insert(id);
+            }
+            entity.id()
+        })
+        .collect::<Vec<_>>();
+    gates::spawn_gates(&mut commands, &saved.gates, &saved.map_size);
+    let visuals = materials
+        .as_deref()
+        .zip(capsule.as_deref().map(|capsule| &capsule.handle));
+    let mut spawned = Vec::with_capacity(saved.navigators.len());
+    for (i, saved_navigator) in saved.navigators.iter().enumerate() {
+        let entity_rng = match saved_navigator.rng_state {
+            Some(state) => EntityRng(fastrand::Rng::with_seed(state)),
+            None => rng.entity_rng(),
+        };
+        let mut entity = spawn_navigator(
+            &mut commands,
+            visuals,
+            i,
+            saved_navigator.transform,
+            saved_navigator.navigator.clone(),
+            entity_rng,
+        );
+        if let Some(path) = &saved_navigator.path {
+            entity.insert(path.clone());
+        }
+        if let Some(shopper) = &saved_navigator.shopper {
+            entity.insert(shopper.clone());
+        }
+        if let Some(archetype) = saved_navigator.archetype {
+            entity.insert(archetypes.bundle(archetype));
+        }
+        if let Some(pace) = &saved_navigator.pace {
+            entity.insert(pace.clone());
+        }
+        if let Some(&stall) = saved_navigator.visiting.and_then(|i| stalls.get(i)) {
+            entity.insert(VisitingStall(stall));
+        }
+        if let Some(dwelling) = &saved_navigator.dwelling {
+            if let Some(&stall) = stalls.get(dwelling.stall) {
+                entity.insert(Dwelling::new(stall, dwelling.remaining));
+            }
+        }
+        if saved_navigator.leaving {
+            entity.insert(Leaving);
+        }
+        if let Some(queueing) = &saved_navigator.queueing {
+            if let Some(&stall) = stalls.get(queueing.stall) {
+                entity.insert(Queueing::new(stall, queueing.patience));
+            }
+        }
+        spawned.push(entity.id());
+    }
+    // Queues are sent to their slots again once the navmesh is built.
+    for (stall, members) in stalls.iter().zip(&saved.queues) {
+        let members = members
+            .iter()
+            .filter_map(|i| spawned.get(*i).copied())
+            .collect();
+        commands
+            .entity(*stall)
+            .insert(StallQueue::with_members(members));
+    }
+    // Followers take their rank in the order they were saved in.
+    let mut ranks = EntityHashMap::default();
+    for (saved_navigator, entity) in saved.navigators.iter().zip(&spawned) {
+        let Some(&leader) = saved_navigator.leader.and_then(|i| spawned.get(i)) else {
+            continue;
+        };
+        let rank = ranks.entry(leader).or_insert(0);
+        commands
+            .entity(*entity)
+            .insert(Follower::new(leader, *rank));
+        if *rank == 0 {
+            commands.entity(leader).insert(GroupLeader::default());
+        }
+        *rank += 1;
+    }
+
+    if let Ok(mut navmesh_update) = navmesh_update.get_single_mut() {
+        *navmesh_update = NavMeshUpdateMode::OnDemand(true);
+    }
+    if let Some(mut changed_mesh) = changed_mesh {
+        changed_mesh.changed = true;
+    }
+    info!(
+        "Loaded {} obstacles, {} stalls and {} units from {}",
+        saved.obstacles.len(),
+        saved.stalls.len(),
+        saved.navigators.len(),
+        event.path.display()
+    );
+}
+
+fn write_save(path: &std::path::Path, saved: &SavedSimulation) -> Result<(), Box<dyn Error>> {
+    let ron = ron::ser::to_string_pretty(saved, ron::ser::PrettyConfig::default())?;
+    std::fs::write(path, ron)?;
+    Ok(())
+}
+
+fn read_save(path: &std::path::Path) -> Result<SavedSimulation, Box<dyn Error>> {
+    let ron = std::fs::read_to_string(path)?;
+    Ok(ron::from_str(&ron)?)
+}
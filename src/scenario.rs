@@ -0,0 +1,521 @@
+//! Scenario files describing a reproducible market: map size, seed, obstacles, stalls, agents
+//! and navmesh settings. They are RON files with the `.scenario.ron` extension, loaded through
+//! the asset server.
+//!
+//! ```ron
+//! (
+//!     map_size: (width: 200.0, depth: 200.0),
+//!     seed: 42,
+//!     navmesh: (simplify: 0.0, merge_steps: 0),
+//!     obstacles: [
+//!         (shape: Rectangle(half_size: (4.0, 1.0)), position: (10.0, -5.0), rotation: 0.0),
+//!     ],
+//!     random_obstacles: 20,
+//!     stalls: [
+//!         (position: (0.0, 20.0), size: (4.0, 3.0), sells: Food, price: 4.0, stock: 15,
+//!          opening_hours: (open: 8.0, close: 18.0)),
+//!     ],
+//!     gates: [
+//!         (side: South, offset: -20.0, kind: Entrance),
+//!         (side: North, kind: Exit),
+//!     ],
+//!     time_of_day: (hours: 7.0, hour_duration: 60.0),
+//!     // Hour of the day and arrivals per minute.
+//!     arrivals: Some([(7.0, 0.0), (9.0, 120.0), (12.0, 200.0), (18.0, 0.0)]),
+//!     agents: [
+//!         (count: 100, center: (0.0, 0.0), half_size: Some((50.0, 50.0))),
+//!     ],
+//!     // Walking speeds in meters per second, by archetype.
+//!     speeds: [
+//!         (Pedestrian, Normal(mean: 1.4, std_dev: 0.2)),
+//!         (Child, Uniform(min: 0.8, max: 1.6)),
+//!     ],
+//! )
+//! ```
+
+use std::{
+    f32::consts::{FRAC_PI_2, PI, TAU},
+    fmt,
+};
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use vleue_navigator::prelude::PrimitiveObstacle;
+
+use crate::{
+    agent3d::spawn_agents,
+    archetype::{Archetype, Archetypes, SpeedDistribution},
+    clearance::PathNavMeshes,
+    clock::TimeOfDay,
+    gates::{self, ArrivalCurve, Gate},
+    group::GroupSettings,
+    spawn_navmesh, spawner,
+    stall::StallDescription,
+    MapSize, MarketConfig, Materials, MyCapsule, NavmeshOptions, SimulationRng,
+};
+
+pub struct ScenarioPlugin;
+
+impl Plugin for ScenarioPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<Scenario>()
+            .register_asset_loader(ScenarioLoader)
+            .add_systems(Startup, load_scenario)
+            .add_systems(
+                Update,
+                (
+                    apply_scenario.run_if(resource_exists::<LoadingScenario>),
+                    spawn_scenario_agents.run_if(resource_exists::<PendingAgents>),
+                ),
+            );
+    }
+}
+
+#[derive(Asset, TypePath, Clone, Debug, Serialize, Deserialize)]
+pub struct Scenario {
+    pub map_size: MapSize,
+    /// Seed for everything random in the scenario.
+    pub seed: u64,
+    #[serde(default)]
+    pub navmesh: NavmeshOptions,
+    #[serde(default)]
+    pub obstacles: Vec<ObstacleDescription>,
+    /// Number of random obstacles added to the explicit ones, placed from the seed.
+    #[serde(default)]
+    pub random_obstacles: u32,
+    #[serde(default)]
+    pub stalls: Vec<StallDescription>,
+    /// Where navigators enter and leave, a gate in the middle of each side when missing.
+    #[serde(default = "Gate::default_gates")]
+    pub gates: Vec<Gate>,
+    #[serde(default)]
+    pub agents: Vec<AgentGroup>,
+    /// Time of day the scenario starts at, and how fast the day goes.
+    #[serde(default)]
+    pub time_of_day: TimeOfDay,
+    /// Arrivals per minute through the entrances over the day, the default profile when missing.
+    #[serde(default)]
+    pub arrivals: Option<Vec<(f32, f32)>>,
+    /// Walking speeds of the archetypes that don't keep their default ones.
+    #[serde(default)]
+    pub speeds: Vec<(Archetype, SpeedDistribution)>,
+}
+
+/// An obstacle lying flat on the ground. Its shape keeps its size, so only a position on the XZ
+/// plane and a rotation around the Y axis are described.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ObstacleDescription {
+    pub shape: ObstacleShape,
+    /// Position on the XZ plane.
+    pub position: (f32, f32),
+    /// Rotation around the Y axis, in radians.
+    #[serde(default)]
+    pub rotation: f32,
+}
+
+/// Largest scale difference or tilt, in radians, an obstacle can have and still be described.
+const TRANSFORM_TOLERANCE: f32 = 1e-4;
+
+impl ObstacleDescription {
+    /// Describes an obstacle placed with `transform`. Its scale and its rotation around the X and
+    /// Z axes are dropped, see [`ObstacleDescription::fits`].
+    pub fn from_transform(shape: ObstacleShape, transform: &Transform) -> Self {
+        let (rotation, _, _) = transform.rotation.to_euler(EulerRot::YXZ);
+        Self {
+            shape,
+            position: (transform.translation.x, transform.translation.z),
+            rotation,
+        }
+    }
+
+    /// Whether `transform` has no scale nor tilt, and is kept whole by
+    /// [`ObstacleDescription::from_transform`].
+    pub fn fits(transform: &Transform) -> bool {
+        let (_, tilt_x, tilt_z) = transform.rotation.to_euler(EulerRot::YXZ);
+        transform.scale.abs_diff_eq(Vec3::ONE, TRANSFORM_TOLERANCE)
+            && tilt_x.abs() <= TRANSFORM_TOLERANCE
+            && tilt_z.abs() <= TRANSFORM_TOLERANCE
+    }
+
+    pub fn transform(&self) -> Transform {
+        Transform::from_xyz(self.position.0, 0.0, self.position.1)
+            .with_rotation(Quat::from_rotation_y(self.rotation))
+    }
+
+    pub fn bundle(&self) -> impl Bundle {
+        (
+            PrimitiveObstacle::from(&self.shape),
+            self.transform(),
+            GlobalTransform::default(),
+        )
+    }
+}
+
+/// Serializable mirror of [`PrimitiveObstacle`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ObstacleShape {
+    Rectangle {
+        half_size: (f32, f32),
+    },
+    Circle {
+        radius: f32,
+    },
+    Ellipse {
+        half_size: (f32, f32),
+    },
+    CircularSector {
+        radius: f32,
+        angle: f32,
+    },
+    CircularSegment {
+        radius: f32,
+        angle: f32,
+    },
+    Capsule {
+        radius: f32,
+        length: f32,
+    },
+    RegularPolygon {
+        circumradius: f32,
+        sides: usize,
+    },
+    Rhombus {
+        horizontal_diagonal: f32,
+        vertical_diagonal: f32,
+    },
+}
+
+impl From<&ObstacleShape> for PrimitiveObstacle {
+    fn from(shape: &ObstacleShape) -> Self {
+        match *shape {
+            ObstacleShape::Rectangle { half_size } => PrimitiveObstacle::Rectangle(Rectangle {
+                half_size: half_size.into(),
+            }),
+            ObstacleShape::Circle { radius } => PrimitiveObstacle::Circle(Circle { radius }),
+            ObstacleShape::Ellipse { half_size } => PrimitiveObstacle::Ellipse(Ellipse {
+                half_size: half_size.into(),
+            }),
+            ObstacleShape::CircularSector { radius, angle } => {
+                PrimitiveObstacle::CircularSector(CircularSector::new(radius, angle))
+            }
+            ObstacleShape::CircularSegment { radius, angle } => {
+                PrimitiveObstacle::CircularSegment(CircularSegment::new(radius, angle))
+            }
+            ObstacleShape::Capsule { radius, length } => {
+                PrimitiveObstacle::Capsule(Capsule2d::new(radius, length))
+            }
+            ObstacleShape::RegularPolygon {
+                circumradius,
+                sides,
+            } => PrimitiveObstacle::RegularPolygon(RegularPolygon::new(circumradius, sides)),
+            ObstacleShape::Rhombus {
+                horizontal_diagonal,
+                vertical_diagonal,
+            } => PrimitiveObstacle::Rhombus(Rhombus::new(horizontal_diagonal, vertical_diagonal)),
+        }
+    }
+}
+
+impl ObstacleShape {
+    /// Radius of a circle centered on the obstacle's origin that contains the whole shape.
+    pub fn bounding_radius(&self) -> f32 {
+        match *self {
+            ObstacleShape::Rectangle { half_size } | ObstacleShape::Ellipse { half_size } => {
+                Vec2::from(half_size).length()
+            }
+            ObstacleShape::Circle { radius }
+            | ObstacleShape::CircularSector { radius, .. }
+            | ObstacleShape::CircularSegment { radius, .. } => radius,
+            ObstacleShape::Capsule { radius, length } => radius + length / 2.0,
+            ObstacleShape::RegularPolygon { circumradius, .. } => circumradius,
+            ObstacleShape::Rhombus {
+                horizontal_diagonal,
+                vertical_diagonal,
+            } => horizontal_diagonal.max(vertical_diagonal) / 2.0,
+        }
+    }
+
+    /// Points around the shape in counterclockwise order, curves approximated by segments. All
+    /// shapes are convex.
+    pub fn outline(&self) -> Vec<Vec2> {
+        const SEGMENTS: usize = 16;
+        let arc = |center: Vec2, radius: f32, from: f32, to: f32| {
+            (0..=SEGMENTS).map(move |i| {
+                center + Vec2::from_angle(from + (to - from) * i as f32 / SEGMENTS as f32) * radius
+            })
+        };
+        match *self {
+            ObstacleShape::Rectangle { half_size } => {
+                let (x, y) = half_size;
+                vec![
+                    Vec2::new(-x, -y),
+                    Vec2::new(x, -y),
+                    Vec2::new(x, y),
+                    Vec2::new(-x, y),
+                ]
+            }
+            ObstacleShape::Circle { radius } => {
+                arc(Vec2::ZERO, radius, 0.0, TAU).take(SEGMENTS).collect()
+            }
+            ObstacleShape::Ellipse { half_size } => arc(Vec2::ZERO, 1.0, 0.0, TAU)
+                .take(SEGMENTS)
+                .map(|point| point * Vec2::from(half_size))
+                .collect(),
+            // Sectors and segments are symmetric around the Y axis.
+            ObstacleShape::CircularSector { radius, angle } => arc(
+                Vec2::ZERO,
+                radius,
+                FRAC_PI_2 - angle / 2.0,
+                FRAC_PI_2 + angle / 2.0,
+            )
+            .chain(std::iter::once(Vec2::ZERO))
+            .collect(),
+            ObstacleShape::CircularSegment { radius, angle } => arc(
+                Vec2::ZERO,
+                radius,
+                FRAC_PI_2 - angle / 2.0,
+                FRAC_PI_2 + angle / 2.0,
+            )
+            .collect(),
+            // Capsules are along the Y axis.
+            ObstacleShape::Capsule { radius, length } => {
+                let half_length = Vec2::Y * length / 2.0;
+                arc(half_length, radius, 0.0, PI)
+                    .chain(arc(-half_length, radius, PI, TAU))
+                    .collect()
+            }
+            // The first vertex is at the top.
+            ObstacleShape::RegularPolygon {
+                circumradius,
+                sides,
+            } => (0..sides)
+                .map(|i| Vec2::from_angle(FRAC_PI_2 + TAU * i as f32 / sides as f32) * circumradius)
+                .collect(),
+            ObstacleShape::Rhombus {
+                horizontal_diagonal,
+                vertical_diagonal,
+            } => {
+                let (x, y) = (horizontal_diagonal / 2.0, vertical_diagonal / 2.0);
+                vec![
+                    Vec2::new(x, 0.0),
+                    Vec2::new(0.0, y),
+                    Vec2::new(-x, 0.0),
+                    Vec2::new(0.0, -y),
+                ]
+            }
+        }
+    }
+
+    /// The description of `obstacle`, if it's a shape this format supports.
+    // `PrimitiveObstacle` can gain shapes that aren't described here yet.
+    #[allow(unreachable_patterns)]
+    pub fn from_primitive(obstacle: &PrimitiveObstacle) -> Option<Self> {
+        Some(match obstacle {
+            PrimitiveObstacle::Rectangle(rectangle) => ObstacleShape::Rectangle {
+                half_size: rectangle.half_size.into(),
+            },
+            PrimitiveObstacle::Circle(circle) => ObstacleShape::Circle {
+                radius: circle.radius,
+            },
+            PrimitiveObstacle::Ellipse(ellipse) => ObstacleShape::Ellipse {
+                half_size: ellipse.half_size.into(),
+            },
+            PrimitiveObstacle::CircularSector(sector) => ObstacleShape::CircularSector {
+                radius: sector.arc.radius,
+                angle: sector.arc.half_angle * 2.0,
+            },
+            PrimitiveObstacle::CircularSegment(segment) => ObstacleShape::CircularSegment {
+                radius: segment.arc.radius,
+                angle: segment.arc.half_angle * 2.0,
+            },
+            PrimitiveObstacle::Capsule(capsule) => ObstacleShape::Capsule {
+                radius: capsule.radius,
+                length: capsule.half_length * 2.0,
+            },
+            PrimitiveObstacle::RegularPolygon(polygon) => ObstacleShape::RegularPolygon {
+                circumradius: polygon.circumcircle.radius,
+                sides: polygon.sides,
+            },
+            PrimitiveObstacle::Rhombus(rhombus) => ObstacleShape::Rhombus {
+                horizontal_diagonal: rhombus.half_diagonals.x * 2.0,
+                vertical_diagonal: rhombus.half_diagonals.y * 2.0,
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// A batch of agents spawned at random walkable positions in a rectangle of the map.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AgentGroup {
+    pub count: u32,
+    /// Center of the spawn area on the XZ plane.
+    #[serde(default)]
+    pub center: (f32, f32),
+    /// Half size of the spawn area, the whole map if not set.
+    #[serde(default)]
+    pub half_size: Option<(f32, f32)>,
+}
+
+#[derive(Default)]
+struct ScenarioLoader;
+
+#[derive(Debug)]
+pub enum ScenarioLoaderError {
+    Io(std::io::Error),
+    Ron(ron::error::SpannedError),
+}
+
+impl fmt::Display for ScenarioLoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScenarioLoaderError::Io(err) => write!(f, "could not read scenario: {err}"),
+            ScenarioLoaderError::Ron(err) => write!(f, "could not parse scenario: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ScenarioLoaderError {}
+
+impl From<std::io::Error> for ScenarioLoaderError {
+    fn from(err: std::io::Error) -> Self {
+        ScenarioLoaderError::Io(err)
+    }
+}
+
+impl From<ron::error::SpannedError> for ScenarioLoaderError {
+    fn from(err: ron::error::SpannedError) -> Self {
+        ScenarioLoaderError::Ron(err)
+    }
+}
+
+impl AssetLoader for ScenarioLoader {
+    type Asset = Scenario;
+    type Settings = ();
+    type Error = ScenarioLoaderError;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<Scenario, ScenarioLoaderError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        Ok(ron::de::from_bytes(&bytes)?)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["scenario.ron"]
+    }
+}
+
+/// The scenario from [`MarketConfig::scenario`], until it's loaded and applied.
+#[derive(Resource)]
+struct LoadingScenario(Handle<Scenario>);
+
+/// Agents of the scenario, waiting for the navmesh to be built.
+#[derive(Resource)]
+struct PendingAgents {
+    groups: Vec<AgentGroup>,
+}
+
+fn load_scenario(
+    mut commands: Commands,
+    config: Res<MarketConfig>,
+    asset_server: Res<AssetServer>,
+) {
+    if let Some(path) = &config.scenario {
+        info!("Loading scenario {path}");
+        commands.insert_resource(LoadingScenario(asset_server.load(path.clone())));
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn apply_scenario(
+    mut commands: Commands,
+    loading: Res<LoadingScenario>,
+    scenarios: Res<Assets<Scenario>>,
+    mut map_size: ResMut<MapSize>,
+    mut rng: ResMut<SimulationRng>,
+    mut time_of_day: ResMut<TimeOfDay>,
+    mut arrivals: ResMut<ArrivalCurve>,
+    mut archetypes: ResMut<Archetypes>,
+) {
+    let Some(scenario) = scenarios.get(&loading.0) else {
+        return;
+    };
+    commands.remove_resource::<LoadingScenario>();
+
+    *map_size = scenario.map_size;
+    spawn_navmesh(&mut commands, &scenario.map_size, &scenario.navmesh);
+
+    // The scenario's seed replaces the one from the configuration.
+    rng.0.seed(scenario.seed);
+    *time_of_day = scenario.time_of_day.clone();
+    if let Some(points) = &scenario.arrivals {
+        arrivals.points.clone_from(points);
+    }
+    for (archetype, speed) in &scenario.speeds {
+        archetypes.profile_mut(*archetype).speed = *speed;
+    }
+    for obstacle in &scenario.obstacles {
+        commands.spawn(obstacle.bundle());
+    }
+    for stall in &scenario.stalls {
+        commands.spawn(stall.bundle());
+    }
+    gates::spawn_gates(&mut commands, &scenario.gates, &scenario.map_size);
+    spawner::spawn_random_obstacles(
+        &mut commands,
+        &mut rng.0,
+        &scenario.map_size,
+        scenario.random_obstacles,
+    );
+
+    commands.insert_resource(PendingAgents {
+        groups: scenario.agents.clone(),
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn spawn_scenario_agents(
+    mut commands: Commands,
+    pending: Res<PendingAgents>,
+    mut rng: ResMut<SimulationRng>,
+    navmeshes: PathNavMeshes,
+    map_size: Res<MapSize>,
+    materials: Option<Res<Materials>>,
+    capsule: Option<Res<MyCapsule>>,
+    archetypes: Res<Archetypes>,
+    groups: Res<GroupSettings>,
+) {
+    let Some(navmeshes) = navmeshes.get() else {
+        return;
+    };
+    let visuals = materials
+        .as_deref()
+        .zip(capsule.as_deref().map(|capsule| &capsule.handle));
+
+    for group in &pending.groups {
+        let area = match group.half_size {
+            Some(half_size) => Rect::from_center_half_size(group.center.into(), half_size.into()),
+            None => map_size.area(),
+        };
+        spawn_agents(
+            &mut commands,
+            visuals,
+            &archetypes,
+            &groups,
+            &navmeshes,
+            &mut rng.0,
+            area,
+            group.count,
+        );
+        info!("Spawned {} units from the scenario", group.count);
+    }
+    commands.remove_resource::<PendingAgents>();
+}
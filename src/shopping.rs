@@ -0,0 +1,306 @@
+//! Shopping lists driving where navigators go. A [`Shopper`] visits the stall with the best
+//! utility for an item of its list, weighing the distance along the navmesh, the price and the
+//! length of the queue. Stalls that sold out or are closed are skipped. Once its list is done, or
+//! nothing it still needs is available and affordable, it walks to the closest exit [`Gate`] it
+//! can reach and leaves the market.
+
+use bevy::{prelude::*, utils::Parallel};
+use fastrand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    agent3d::{give_target_to_navigator, Navigator, OffMesh, Path, SimulationStats},
+    clearance::SizeClass,
+    clock::TimeOfDay,
+    economy::Stock,
+    gates::Gate,
+    path_requests::{ChoiceRequest, PathChoice, PathRequests, WaitingForPath},
+    queue::{update_queues, Queueing, StallQueue},
+    stall::Stall,
+};
+
+/// How close to an exit a leaving navigator must be to leave the market.
+const EXIT_DISTANCE: f32 = 3.0;
+
+pub struct ShoppingPlugin;
+
+impl Plugin for ShoppingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChoiceWeights>().add_systems(
+            FixedUpdate,
+            (leave_market, choose_destinations)
+                .chain()
+                .after(update_queues)
+                .before(give_target_to_navigator),
+        );
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ItemKind {
+    Food,
+    Drink,
+    #[default]
+    Goods,
+}
+
+impl ItemKind {
+    pub const ALL: [ItemKind; 3] = [ItemKind::Food, ItemKind::Drink, ItemKind::Goods];
+
+    pub fn random(rng: &mut Rng) -> Self {
+        Self::ALL[rng.usize(..Self::ALL.len())]
+    }
+}
+
+/// What a navigator still has to buy, and the money it has left for it.
+#[derive(Component, Clone, Debug, Serialize, Deserialize)]
+pub struct Shopper {
+    pub shopping_list: Vec<ItemKind>,
+    pub budget: f32,
+}
+
+impl Shopper {
+    /// A list of one to four items, with enough money for most of it.
+    pub fn random(rng: &mut Rng) -> Self {
+        Self {
+            shopping_list: (0..rng.usize(1..=4))
+                .map(|_| ItemKind::random(rng))
+                .collect(),
+            budget: rng.f32() * 40.0 + 10.0,
+        }
+    }
+
+    /// Whether an item sold at `price` is on the list and affordable.
+    pub fn wants(&self, item: ItemKind, price: f32) -> bool {
+        price <= self.budget && self.shopping_list.contains(&item)
+    }
+
+    /// Crosses `item` off the list and pays for it.
+    pub fn buy(&mut self, item: ItemKind, price: f32) {
+        if let Some(index) = self.shopping_list.iter().position(|wanted| *wanted == item) {
+            self.shopping_list.remove(index);
+            self.budget -= price;
+        }
+    }
+}
+
+/// Marks a navigator walking to an exit.
+#[derive(Component)]
+pub struct Leaving;
+
+/// How much each criteria weighs when choosing a stall. The stall with the lowest weighted sum
+/// is chosen.
+///
+/// The paths to the candidates are searched through [`PathRequests`], and the navigator decides
+/// once their lengths are known.
+#[derive(Resource, Clone, Debug)]
+pub struct ChoiceWeights {
+    /// Cost of each meter to walk along the navmesh.
+    pub distance: f32,
+    /// Cost of each unit of money spent.
+    pub price: f32,
+    /// Cost of each navigator already in the queue.
+    pub queue: f32,
+    /// Number of stalls, the closest ones in straight line, compared by each navigator.
+    pub candidates: usize,
+}
+
+impl Default for ChoiceWeights {
+    fn default() -> Self {
+        Self {
+            distance: 1.0,
+            price: 5.0,
+            queue: 10.0,
+            candidates: 4,
+        }
+    }
+}
+
+/// Points where navigators can leave the market.
+fn exit_points(gates: &Query<(&Gate, &Transform)>) -> Vec<Vec3> {
+    gates
+        .iter()
+        .filter(|(gate, _)| gate.kind.is_exit())
+        .map(|(_, transform)| transform.translation)
+        .collect()
+}
+
+/// A stall a navigator could visit.
+struct Offer {
+    stall: Entity,
+    entry_point: Vec3,
+    item: ItemKind,
+    price: f32,
+    queue_length: usize,
+}
+
+enum Decision {
+    /// Compare the paths to the stalls of the request, and visit the cheapest one.
+    Visit(ChoiceRequest),
+    /// Compare the paths to the exits of the request, and leave through the closest one. The
+    /// request is `None` if there are no exits.
+    Leave {
+        entity: Entity,
+        request: Option<ChoiceRequest>,
+    },
+}
+
+impl Decision {
+    fn entity(&self) -> Entity {
+        match self {
+            Decision::Visit(request) => request.entity,
+            Decision::Leave { entity, .. } => *entity,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn choose_destinations(
+    mut commands: Commands,
+    shoppers: Query<
+        (Entity, &Transform, &Shopper, Option<&SizeClass>),
+        (
+            With<Navigator>,
+            Without<Path>,
+            Without<OffMesh>,
+            Without<WaitingForPath>,
+            Without<Queueing>,
+            Without<Leaving>,
+        ),
+    >,
+    stalls: Query<(Entity, &Stall, &StallQueue, Option<&Stock>)>,
+    gates: Query<(&Gate, &Transform)>,
+    weights: Res<ChoiceWeights>,
+    time_of_day: Res<TimeOfDay>,
+    mut requests: ResMut<PathRequests>,
+    mut stats: ResMut<SimulationStats>,
+    mut decisions: Local<Parallel<Vec<Decision>>>,
+) {
+    let mut offers = stalls
+        .iter()
+        .filter(|(_, stall, queue, stock)| {
+            !queue.is_full()
+                && !stock.is_some_and(|stock| stock.is_empty())
+                && stall.opening_hours.is_open(time_of_day.hours())
+        })
+        .filter_map(|(stall_entity, stall, queue, _)| {
+            Some(Offer {
+                stall: stall_entity,
+                entry_point: queue.entry_point()?,
+                item: stall.sells,
+                price: stall.price,
+                queue_length: queue.len(),
+            })
+        })
+        .collect::<Vec<_>>();
+    // Equal costs are broken the same way on every run.
+    offers.sort_unstable_by_key(|offer| offer.stall);
+    let mut exits = exit_points(&gates);
+    // Exits as far as each other are chosen between the same way on every run.
+    exits.sort_unstable_by(|a, b| a.x.total_cmp(&b.x).then(a.z.total_cmp(&b.z)));
+
+    shoppers
+        .par_iter()
+        .for_each(|(entity, transform, shopper, size_class)| {
+            let position = transform.translation;
+            let request = |options: Vec<PathChoice>, distance_weight: f32| ChoiceRequest {
+                entity,
+                from: position,
+                size_class: size_class.copied().unwrap_or_default(),
+                options,
+                distance_weight,
+            };
+            let mut candidates = offers
+                .iter()
+                .filter(|offer| shopper.wants(offer.item, offer.price))
+                .collect::<Vec<_>>();
+            candidates.sort_by(|a, b| {
+                a.entry_point
+                    .distance_squared(position)
+                    .total_cmp(&b.entry_point.distance_squared(position))
+            });
+            candidates.truncate(weights.candidates);
+
+            let decision = if candidates.is_empty() {
+                // Exits that can't be reached are skipped for the next closest one.
+                let options = exits
+                    .iter()
+                    .map(|exit| PathChoice {
+                        to: *exit,
+                        cost: 0.0,
+                        stall: None,
+                    })
+                    .collect::<Vec<_>>();
+                Decision::Leave {
+                    entity,
+                    request: (!options.is_empty()).then(|| request(options, 1.0)),
+                }
+            } else {
+                let options = candidates
+                    .into_iter()
+                    .map(|offer| PathChoice {
+                        to: offer.entry_point,
+                        cost: weights.price * offer.price
+                            + weights.queue * offer.queue_length as f32,
+                        stall: Some(offer.stall),
+                    })
+                    .collect();
+                Decision::Visit(request(options, weights.distance))
+            };
+            decisions.borrow_local_mut().push(decision);
+        });
+
+    let mut all_decisions = Vec::new();
+    decisions.drain_into(&mut all_decisions);
+    all_decisions.sort_unstable_by_key(Decision::entity);
+    for decision in all_decisions {
+        match decision {
+            Decision::Visit(request) => {
+                commands.entity(request.entity).insert(WaitingForPath);
+                requests.push_choice(request);
+            }
+            Decision::Leave {
+                request: Some(request),
+                ..
+            } => {
+                commands
+                    .entity(request.entity)
+                    .insert((WaitingForPath, Leaving));
+                requests.push_choice(request);
+            }
+            // There is no exit, leave from here.
+            Decision::Leave {
+                entity,
+                request: None,
+            } => {
+                commands.entity(entity).despawn_recursive();
+                stats.left += 1;
+            }
+        }
+    }
+}
+
+/// Removes the navigators that reached an exit. Those that stopped elsewhere will choose where to
+/// go again.
+fn leave_market(
+    mut commands: Commands,
+    navigators: Query<
+        (Entity, &Transform),
+        (With<Leaving>, Without<Path>, Without<WaitingForPath>),
+    >,
+    gates: Query<(&Gate, &Transform)>,
+    mut stats: ResMut<SimulationStats>,
+) {
+    let exits = exit_points(&gates);
+    for (entity, transform) in &navigators {
+        let at_exit = exits
+            .iter()
+            .any(|exit| exit.xz().distance(transform.translation.xz()) <= EXIT_DISTANCE);
+        if at_exit {
+            commands.entity(entity).despawn_recursive();
+            stats.left += 1;
+        } else {
+            commands.entity(entity).remove::<Leaving>();
+        }
+    }
+}
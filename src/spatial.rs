@@ -0,0 +1,158 @@
+//! Uniform grid over the XZ plane of the map, answering "who is near me" queries about
+//! navigators without iterating all of them.
+
+use bevy::prelude::*;
+
+use crate::{agent3d::Navigator, MapSize};
+
+/// Default size of a grid cell, in meters.
+pub const DEFAULT_CELL_SIZE: f32 = 10.0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct GridEntry {
+    pub entity: Entity,
+    pub position: Vec2,
+}
+
+/// Positions of all navigators, bucketed in cells covering the map. It is rebuilt each frame
+/// before the navigators move, and queries only need `&self` so they can be used from parallel
+/// systems. Positions outside the map are kept in the closest edge cell.
+#[derive(Resource)]
+pub struct SpatialGrid {
+    cell_size: f32,
+    origin: Vec2,
+    dimensions: UVec2,
+    /// Index in `entries` of the first entry of each cell, row by row, followed by the number of
+    /// entries. The entries of a cell are `entries[cell_starts[i]..cell_starts[i + 1]]`.
+    cell_starts: Vec<usize>,
+    entries: Vec<GridEntry>,
+    /// Scratch buffers kept between rebuilds: the cell of each item, and where the next entry of
+    /// each cell goes.
+    cells: Vec<usize>,
+    cursors: Vec<usize>,
+}
+
+impl Default for SpatialGrid {
+    fn default() -> Self {
+        Self::new(DEFAULT_CELL_SIZE)
+    }
+}
+
+impl SpatialGrid {
+    pub fn new(cell_size: f32) -> Self {
+        Self {
+            cell_size,
+            origin: Vec2::ZERO,
+            dimensions: UVec2::ZERO,
+            cell_starts: Vec::new(),
+            entries: Vec::new(),
+            cells: Vec::new(),
+            cursors: Vec::new(),
+        }
+    }
+
+    pub fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Replaces the content of the grid, sized to cover `map_size`.
+    pub fn rebuild(&mut self, map_size: &MapSize, items: impl IntoIterator<Item = GridEntry>) {
+        self.origin = -map_size.half_size();
+        self.dimensions = (Vec2::new(map_size.width, map_size.depth) / self.cell_size)
+            .ceil()
+            .as_uvec2()
+            .max(UVec2::ONE);
+
+        let items = items.into_iter().collect::<Vec<_>>();
+        let cell_count = (self.dimensions.x * self.dimensions.y) as usize;
+        self.cell_starts.clear();
+        self.cell_starts.resize(cell_count + 1, 0);
+        let mut cells = std::mem::take(&mut self.cells);
+        cells.clear();
+        cells.extend(items.iter().map(|item| self.cell_index(item.position)));
+        for &cell in &cells {
+            self.cell_starts[cell + 1] += 1;
+        }
+        for i in 0..cell_count {
+            self.cell_starts[i + 1] += self.cell_starts[i];
+        }
+
+        self.cursors.clone_from(&self.cell_starts);
+        self.entries.clear();
+        self.entries.resize(
+            items.len(),
+            GridEntry {
+                entity: Entity::PLACEHOLDER,
+                position: Vec2::ZERO,
+            },
+        );
+        for (item, &cell) in items.into_iter().zip(&cells) {
+            self.entries[self.cursors[cell]] = item;
+            self.cursors[cell] += 1;
+        }
+        self.cells = cells;
+    }
+
+    fn cell_coordinates(&self, position: Vec2) -> UVec2 {
+        ((position - self.origin) / self.cell_size)
+            .floor()
+            .as_ivec2()
+            .clamp(IVec2::ZERO, self.dimensions.as_ivec2() - IVec2::ONE)
+            .as_uvec2()
+    }
+
+    fn cell_index(&self, position: Vec2) -> usize {
+        let cell = self.cell_coordinates(position);
+        (cell.y * self.dimensions.x + cell.x) as usize
+    }
+
+    /// Entries inside `rect`, a rectangle on the XZ plane.
+    pub fn query_rect(&self, rect: Rect) -> impl Iterator<Item = &GridEntry> + '_ {
+        let (min, max) = if self.entries.is_empty() {
+            // Nothing to find, and the grid may not have been sized yet.
+            (UVec2::ONE, UVec2::ZERO)
+        } else {
+            (
+                self.cell_coordinates(rect.min),
+                self.cell_coordinates(rect.max),
+            )
+        };
+        // The cells of a row are contiguous, so each row of the query is a single slice.
+        (min.y..=max.y)
+            .flat_map(move |y| {
+                let row = (y * self.dimensions.x) as usize;
+                let start = self.cell_starts[row + min.x as usize];
+                let end = self.cell_starts[row + max.x as usize + 1];
+                self.entries[start..end].iter()
+            })
+            .filter(move |entry| rect.contains(entry.position))
+    }
+
+    /// Entries within `radius` of `center`.
+    pub fn query_radius(&self, center: Vec2, radius: f32) -> impl Iterator<Item = &GridEntry> + '_ {
+        self.query_rect(Rect::from_center_half_size(center, Vec2::splat(radius)))
+            .filter(move |entry| entry.position.distance_squared(center) <= radius * radius)
+    }
+}
+
+pub fn update_spatial_grid(
+    mut grid: ResMut<SpatialGrid>,
+    map_size: Res<MapSize>,
+    navigators: Query<(Entity, &Transform), With<Navigator>>,
+) {
+    grid.rebuild(
+        &map_size,
+        navigators.iter().map(|(entity, transform)| GridEntry {
+            entity,
+            position: transform.translation.xz(),
+        }),
+    );
+}
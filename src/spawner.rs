@@ -2,22 +2,30 @@ use std::{f32::consts::PI, time::Instant};
 
 use bevy::{math::vec2, prelude::*, window::PrimaryWindow};
 use fastrand::Rng;
-use vleue_navigator::{
-    prelude::{NavMeshUpdateMode, PrimitiveObstacle},
-    NavMesh,
-};
+use vleue_navigator::prelude::{NavMeshUpdateMode, PrimitiveObstacle};
 
 use crate::{
     agent3d::{spawn_agents, Navigator},
-    ChangedMesh, Materials, MyCapsule, MyGroundPlane, Navmeshes,
+    archetype::Archetypes,
+    clearance::{ClassNavMesh, PathNavMeshes},
+    group::GroupSettings,
+    ChangedMesh, MapSize, MarketConfig, Materials, MyCapsule, MyGroundPlane, SimulationRng,
 };
 
 pub struct SpawnerPlugin;
 
 impl Plugin for SpawnerPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(SpawnedUnits { count: 0 })
-            .add_systems(Update, (spawn_units, spawn_obstacle));
+        app.insert_resource(SpawnedUnits { count: 0 }).add_systems(
+            Update,
+            (
+                spawn_units,
+                // Placing obstacles needs a window to pick from and the market visuals.
+                spawn_obstacle.run_if(
+                    any_with_component::<PrimaryWindow>.and_then(resource_exists::<ChangedMesh>),
+                ),
+            ),
+        );
     }
 }
 
@@ -26,28 +34,36 @@ struct SpawnedUnits {
     count: u32,
 }
 
+#[allow(clippy::too_many_arguments)]
 fn spawn_units(
-    commands: Commands,
-    materials: Res<Materials>,
-    navmeshes: Res<Assets<NavMesh>>,
-    navmesh: Query<&Handle<NavMesh>>,
+    mut commands: Commands,
+    materials: Option<Res<Materials>>,
+    navmeshes: PathNavMeshes,
     input: Res<ButtonInput<KeyCode>>,
     mut spawned_units: ResMut<SpawnedUnits>,
-    capsule: Res<MyCapsule>,
+    capsule: Option<Res<MyCapsule>>,
+    config: Res<MarketConfig>,
+    map_size: Res<MapSize>,
+    mut rng: ResMut<SimulationRng>,
+    archetypes: Res<Archetypes>,
+    groups: Res<GroupSettings>,
 ) {
     if input.just_pressed(KeyCode::KeyP) {
-        let count = 10000;
-        let Ok(navmesh_id) = navmesh.get_single() else {
-            return;
-        };
-        let Some(navmesh) = navmeshes.get(navmesh_id) else {
+        let count = config.spawn_batch;
+        let Some(navmeshes) = navmeshes.get() else {
             return;
         };
+        let visuals = materials
+            .as_deref()
+            .zip(capsule.as_deref().map(|capsule| &capsule.handle));
         spawn_agents(
-            commands,
-            materials.into_inner(),
-            &capsule.handle,
-            navmesh,
+            &mut commands,
+            visuals,
+            &archetypes,
+            &groups,
+            &navmeshes,
+            &mut rng.0,
+            map_size.area(),
             count,
         );
         spawned_units.count += count;
@@ -58,6 +74,11 @@ fn spawn_units(
     }
 }
 
+/// Marks the cube showing where an obstacle was placed with the mouse.
+#[derive(Component)]
+pub struct ObstacleVisual;
+
+#[allow(clippy::too_many_arguments)]
 fn spawn_obstacle(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
@@ -71,7 +92,8 @@ fn spawn_obstacle(
     // query to get ground plane's transform
     q_plane: Query<&GlobalTransform, With<MyGroundPlane>>,
     mut changed_mesh: ResMut<ChangedMesh>,
-    mut navmesh_update: Query<&mut NavMeshUpdateMode>,
+    mut navmesh_update: Query<&mut NavMeshUpdateMode, Without<ClassNavMesh>>,
+    mut rng: ResMut<SimulationRng>,
 ) {
     if input.just_pressed(MouseButton::Right) {
         // let Ok(navmesh_id) = navmesh.get_single() else {
@@ -81,8 +103,6 @@ fn spawn_obstacle(
         //     return;
         // };
 
-        let mut rng = Rng::new();
-
         let (camera, camera_transform) = q_camera.single();
         let ground_transform = q_plane.single();
         let window = q_window.single();
@@ -104,21 +124,39 @@ fn spawn_obstacle(
         let transform = Transform::from_translation(global_cursor);
 
         println!("Spawning obstacle at {:?}", transform);
-        commands.spawn(PbrBundle {
-            mesh: meshes.add(Mesh::from(Cuboid::new(1.0, 1.0, 1.0))),
-            material: materials.add(Color::srgb(0.8, 0.7, 0.6)),
-            transform,
-            ..default()
-        });
+        commands.spawn((
+            PbrBundle {
+                mesh: meshes.add(Mesh::from(Cuboid::new(1.0, 1.0, 1.0))),
+                material: materials.add(Color::srgb(0.8, 0.7, 0.6)),
+                transform,
+                ..default()
+            },
+            ObstacleVisual,
+        ));
         changed_mesh.changed = true;
 
-        new_obstacle(&mut commands, &mut rng, transform);
+        new_obstacle(&mut commands, &mut rng.0, transform);
         if let Ok(mut navmesh_update) = navmesh_update.get_single_mut() {
             *navmesh_update = NavMeshUpdateMode::OnDemand(true);
         }
     }
 }
 
+/// Spawns `count` random obstacles scattered over the whole map.
+pub fn spawn_random_obstacles(
+    commands: &mut Commands,
+    rng: &mut Rng,
+    map_size: &MapSize,
+    count: u32,
+) {
+    for _ in 0..count {
+        let x = rng.f32() * map_size.width - map_size.width / 2.0;
+        let z = rng.f32() * map_size.depth - map_size.depth / 2.0;
+        let transform = Transform::from_translation(Vec3::new(x, 0.0, z));
+        new_obstacle(commands, rng, transform);
+    }
+}
+
 pub fn new_obstacle(commands: &mut Commands, rng: &mut Rng, transform: Transform) {
     commands.spawn((
         match rng.u32(0..8) {
@@ -0,0 +1,413 @@
+//! Market stalls. A stall is a rectangular obstacle with an access point on one of its walkable
+//! sides. Navigators pick a stall, queue in front of it, are served at its access point for the
+//! stall's dwell time, then pick another one. What they buy is handled by the
+//! [`economy`](crate::economy).
+//!
+//! Stalls only serve during their [`OpeningHours`]. When a stall closes, the navigators waiting
+//! in its queue go elsewhere.
+
+use std::f32::consts::FRAC_PI_2;
+
+use bevy::prelude::*;
+use fastrand::Rng;
+use serde::{Deserialize, Serialize};
+use vleue_navigator::prelude::*;
+
+use crate::{
+    agent3d::{give_target_to_navigator, SimulationStats},
+    clearance::ClassNavMesh,
+    economy::{Demand, Ledger, Stock, DEFAULT_RESTOCK_TIME, DEFAULT_STOCK},
+    queue::{
+        join_queues, lose_patience, queue_slots, update_queues, QueueSettings, Queueing, StallQueue,
+    },
+    shopping::ItemKind,
+    MapSize,
+};
+
+/// Time navigators stay at a stall when not specified, in seconds.
+pub const DEFAULT_DWELL_TIME: f32 = 5.0;
+/// Price of the item sold by a stall when not specified.
+pub const DEFAULT_PRICE: f32 = 5.0;
+/// Distance between the footprint of a stall and its access point.
+const ACCESS_MARGIN: f32 = 1.5;
+/// Height of the mesh displaying a stall.
+const STALL_HEIGHT: f32 = 2.5;
+
+pub struct StallPlugin;
+
+impl Plugin for StallPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<QueueSettings>()
+            .add_event::<VisitCompleted>()
+            .add_systems(
+                FixedUpdate,
+                (
+                    assign_stall_ids,
+                    place_access_points,
+                    join_queues,
+                    lose_patience,
+                    dwell_at_stalls,
+                    update_queues,
+                )
+                    .chain()
+                    .before(give_target_to_navigator),
+            );
+    }
+}
+
+#[derive(Component, Clone, Debug)]
+pub struct Stall {
+    /// Size of the footprint on the XZ plane, before rotation.
+    pub size: Vec2,
+    /// Time navigators stay at the stall, in seconds.
+    pub dwell_time: f32,
+    /// What the stall sells.
+    pub sells: ItemKind,
+    /// Current price, set from the [`Demand`] of the stall.
+    pub price: f32,
+    pub opening_hours: OpeningHours,
+    /// Where navigators are served, next to a walkable side of the stall. `None` until the
+    /// navmesh is built, or if all sides are blocked.
+    access_point: Option<Vec3>,
+}
+
+impl Stall {
+    pub fn new(
+        size: Vec2,
+        dwell_time: f32,
+        sells: ItemKind,
+        price: f32,
+        opening_hours: OpeningHours,
+    ) -> Self {
+        Self {
+            size,
+            dwell_time,
+            sells,
+            price,
+            opening_hours,
+            access_point: None,
+        }
+    }
+
+    pub fn access_point(&self) -> Option<Vec3> {
+        self.access_point
+    }
+
+    /// Candidate access points in front of each side of the stall, front side first.
+    fn access_candidates(&self, transform: &Transform) -> [Vec3; 4] {
+        let half_size = self.size / 2.0;
+        [
+            Vec3::new(0.0, 0.0, half_size.y + ACCESS_MARGIN),
+            Vec3::new(half_size.x + ACCESS_MARGIN, 0.0, 0.0),
+            Vec3::new(-half_size.x - ACCESS_MARGIN, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -half_size.y - ACCESS_MARGIN),
+        ]
+        .map(|offset| {
+            let point = transform.transform_point(offset);
+            // Navigators walk at this height.
+            Vec3::new(point.x, 1.75, point.z)
+        })
+    }
+}
+
+/// Identifies a stall across saves, unlike its entity. Given to stalls spawned without one.
+#[derive(
+    Component, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct StallId(pub u32);
+
+/// Hours of the day a stall serves, from `open` to `close`. Stalls open at night close after
+/// midnight.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct OpeningHours {
+    pub open: f32,
+    pub close: f32,
+}
+
+impl Default for OpeningHours {
+    fn default() -> Self {
+        Self {
+            open: 8.0,
+            close: 20.0,
+        }
+    }
+}
+
+impl OpeningHours {
+    /// Whether the stall serves at `hours` since midnight.
+    pub fn is_open(&self, hours: f32) -> bool {
+        if self.open <= self.close {
+            hours >= self.open && hours < self.close
+        } else {
+            hours >= self.open || hours < self.close
+        }
+    }
+}
+
+/// A stall as written in scenario and save files.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StallDescription {
+    /// Position on the XZ plane.
+    pub position: (f32, f32),
+    /// Rotation around the Y axis, in radians.
+    #[serde(default)]
+    pub rotation: f32,
+    pub size: (f32, f32),
+    #[serde(default = "default_dwell_time")]
+    pub dwell_time: f32,
+    #[serde(default)]
+    pub sells: ItemKind,
+    /// Base price, before adjusting to demand.
+    #[serde(default = "default_price")]
+    pub price: f32,
+    /// Number of items the stall holds when fully stocked.
+    #[serde(default = "default_stock")]
+    pub stock: u32,
+    /// Time to restock a single item, in seconds.
+    #[serde(default = "default_restock_time")]
+    pub restock_time: f32,
+    #[serde(default)]
+    pub opening_hours: OpeningHours,
+    /// Given when the stall is spawned if missing.
+    #[serde(default)]
+    pub id: Option<StallId>,
+    /// Items left, the stall is fully stocked if missing.
+    #[serde(default)]
+    pub quantity: Option<u32>,
+    /// Recent sales the price is set from, see [`Demand`].
+    #[serde(default)]
+    pub recent_sales: f32,
+}
+
+fn default_dwell_time() -> f32 {
+    DEFAULT_DWELL_TIME
+}
+
+fn default_price() -> f32 {
+    DEFAULT_PRICE
+}
+
+fn default_stock() -> u32 {
+    DEFAULT_STOCK
+}
+
+fn default_restock_time() -> f32 {
+    DEFAULT_RESTOCK_TIME
+}
+
+impl StallDescription {
+    pub fn from_stall(
+        stall: &Stall,
+        id: Option<StallId>,
+        transform: &Transform,
+        stock: &Stock,
+        demand: &Demand,
+    ) -> Self {
+        let (rotation, _, _) = transform.rotation.to_euler(EulerRot::YXZ);
+        Self {
+            position: (transform.translation.x, transform.translation.z),
+            rotation,
+            size: stall.size.into(),
+            dwell_time: stall.dwell_time,
+            sells: stall.sells,
+            price: demand.base_price,
+            stock: stock.capacity,
+            restock_time: stock.restock_time,
+            opening_hours: stall.opening_hours,
+            id,
+            quantity: Some(stock.quantity),
+            recent_sales: demand.recent_sales(),
+        }
+    }
+
+    /// The stall, registered as an obstacle of the navmesh. Its [`StallId`] is inserted
+    /// separately.
+    pub fn bundle(&self) -> impl Bundle {
+        let size = Vec2::from(self.size);
+        let mut stock = Stock::new(self.stock, self.restock_time);
+        if let Some(quantity) = self.quantity {
+            stock.quantity = quantity.min(self.stock);
+        }
+        (
+            PrimitiveObstacle::Rectangle(Rectangle {
+                half_size: size / 2.0,
+            }),
+            Transform::from_xyz(self.position.0, 0.0, self.position.1)
+                .with_rotation(Quat::from_rotation_y(self.rotation)),
+            GlobalTransform::default(),
+            Stall::new(
+                size,
+                self.dwell_time,
+                self.sells,
+                self.price,
+                self.opening_hours,
+            ),
+            StallQueue::default(),
+            stock,
+            Demand::new(self.price).with_recent_sales(self.recent_sales),
+        )
+    }
+}
+
+/// Sent when a navigator has been served at a stall.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct VisitCompleted {
+    pub navigator: Entity,
+    pub stall: Entity,
+}
+
+/// The stall a navigator is walking to.
+#[derive(Component)]
+pub struct VisitingStall(pub Entity);
+
+/// A navigator being served at a stall.
+#[derive(Component)]
+pub struct Dwelling {
+    pub stall: Entity,
+    remaining: f32,
+}
+
+impl Dwelling {
+    pub(crate) fn new(stall: Entity, duration: f32) -> Self {
+        Self {
+            stall,
+            remaining: duration,
+        }
+    }
+
+    /// Time left before being served, in seconds.
+    pub fn remaining(&self) -> f32 {
+        self.remaining
+    }
+}
+
+/// Spawns `count` stalls of random sizes scattered over the whole map.
+pub fn spawn_random_stalls(commands: &mut Commands, rng: &mut Rng, map_size: &MapSize, count: u32) {
+    for _ in 0..count {
+        let description = StallDescription {
+            position: (
+                rng.f32() * map_size.width - map_size.width / 2.0,
+                rng.f32() * map_size.depth - map_size.depth / 2.0,
+            ),
+            // Stalls are aligned with the map, facing any of the four directions.
+            rotation: rng.u32(0..4) as f32 * FRAC_PI_2,
+            size: (rng.f32() * 3.0 + 3.0, rng.f32() * 2.0 + 2.0),
+            dwell_time: rng.f32() * 7.0 + 3.0,
+            sells: ItemKind::random(rng),
+            price: rng.f32() * 10.0 + 2.0,
+            stock: rng.u32(10..=30),
+            restock_time: rng.f32() * 10.0 + 5.0,
+            opening_hours: OpeningHours {
+                open: rng.u32(7..=9) as f32,
+                close: rng.u32(18..=21) as f32,
+            },
+            id: None,
+            quantity: None,
+            recent_sales: 0.0,
+        };
+        commands.spawn(description.bundle());
+    }
+}
+
+/// Gives an id to the stalls spawned without one, after the highest one in use or in the
+/// [`Ledger`], so that sales of removed stalls aren't counted for new ones.
+fn assign_stall_ids(
+    mut commands: Commands,
+    new_stalls: Query<Entity, (With<Stall>, Without<StallId>)>,
+    ids: Query<&StallId>,
+    ledger: Res<Ledger>,
+) {
+    if new_stalls.is_empty() {
+        return;
+    }
+    let mut next = ids
+        .iter()
+        .chain(ledger.sales().iter().map(|sale| &sale.stall))
+        .map(|id| id.0 + 1)
+        .max()
+        .unwrap_or(0);
+    let mut new_stalls = new_stalls.iter().collect::<Vec<_>>();
+    // Stalls spawned together are numbered the same way on every run.
+    new_stalls.sort_unstable();
+    for entity in new_stalls {
+        commands.entity(entity).insert(StallId(next));
+        next += 1;
+    }
+}
+
+/// Moves the access points of the stalls to a walkable side, and their queue along it, each
+/// time the navmesh is rebuilt.
+fn place_access_points(
+    mut stalls: Query<(&mut Stall, &mut StallQueue, &Transform)>,
+    navmeshes: Res<Assets<NavMesh>>,
+    navmesh: Query<(&Handle<NavMesh>, Ref<NavMeshStatus>), Without<ClassNavMesh>>,
+    settings: Res<QueueSettings>,
+) {
+    let Ok((navmesh_handle, status)) = navmesh.get_single() else {
+        return;
+    };
+    if !status.is_changed() || !matches!(*status, NavMeshStatus::Built) {
+        return;
+    }
+    let Some(navmesh) = navmeshes.get(navmesh_handle) else {
+        return;
+    };
+    for (mut stall, mut queue, transform) in &mut stalls {
+        stall.access_point = stall
+            .access_candidates(transform)
+            .into_iter()
+            .find(|point| navmesh.transformed_is_in_mesh(*point));
+        let slots = stall.access_point.map_or_else(Vec::new, |access_point| {
+            let outward = (access_point - transform.translation)
+                .xz()
+                .normalize_or_zero();
+            queue_slots(navmesh, access_point, outward, &settings)
+        });
+        queue.set_slots(slots);
+    }
+}
+
+/// Lets the navigators that have been served leave the queue.
+pub(crate) fn dwell_at_stalls(
+    mut commands: Commands,
+    mut navigators: Query<(Entity, &mut Dwelling)>,
+    mut visits: EventWriter<VisitCompleted>,
+    mut stats: ResMut<SimulationStats>,
+    time: Res<Time>,
+) {
+    for (entity, mut dwelling) in &mut navigators {
+        dwelling.remaining -= time.delta_seconds();
+        if dwelling.remaining > 0.0 {
+            continue;
+        }
+        commands.entity(entity).remove::<(Dwelling, Queueing)>();
+        stats.served += 1;
+        visits.send(VisitCompleted {
+            navigator: entity,
+            stall: dwelling.stall,
+        });
+    }
+}
+
+/// Gives a box mesh to the stalls, when rendering.
+pub fn add_stall_meshes(
+    mut commands: Commands,
+    stalls: Query<(Entity, &Stall), Added<Stall>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut material: Local<Option<Handle<StandardMaterial>>>,
+) {
+    let material = material
+        .get_or_insert_with(|| materials.add(Color::srgb(0.8, 0.4, 0.2)))
+        .clone();
+    for (entity, stall) in &stalls {
+        commands.entity(entity).insert((
+            meshes.add(
+                Mesh::from(Cuboid::new(stall.size.x, STALL_HEIGHT, stall.size.y))
+                    .translated_by(Vec3::Y * STALL_HEIGHT / 2.0),
+            ),
+            material.clone(),
+            VisibilityBundle::default(),
+        ));
+    }
+}
@@ -0,0 +1,136 @@
+//! Kinematic steering. Navigators don't change velocity at once: they speed up and slow down at a
+//! limited rate and turn at a limited rate, slowing down for sharp turns. They also slow down
+//! when arriving at the end of their path, and face the direction they walk in.
+//!
+//! The velocity found by the collision avoidance is the one navigators steer towards, the one
+//! they actually move at is limited by their [`Steering`]. Avoidance doesn't know about
+//! obstacles: when its velocity would take a navigator off the navmesh, it steers towards the
+//! velocity following its path instead.
+
+use std::f32::consts::PI;
+
+use bevy::prelude::*;
+use vleue_navigator::NavMesh;
+
+use crate::{
+    agent3d::move_navigator,
+    avoidance::{avoid_collisions, Velocity},
+    clearance::ClassNavMesh,
+};
+
+/// Navigators slower than this keep facing the same direction.
+const MIN_FACING_SPEED: f32 = 0.1;
+/// Speed of navigators reaching the end of their path relative to their speed, so they don't
+/// crawl to it.
+const MIN_ARRIVAL_FACTOR: f32 = 0.25;
+/// Share of the arrival distance, or of the corner radius if smaller, within which a waypoint is
+/// reached.
+const REACHED_FACTOR: f32 = 0.25;
+
+pub struct SteeringPlugin;
+
+impl Plugin for SteeringPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            FixedUpdate,
+            steer.after(avoid_collisions).before(move_navigator),
+        );
+    }
+}
+
+/// Limits on how fast a navigator changes its velocity.
+#[derive(Component, Clone, Debug)]
+pub struct Steering {
+    /// Maximum change of speed per second.
+    pub max_acceleration: f32,
+    /// Maximum change of direction, in radians per second.
+    pub max_turn_rate: f32,
+    /// Navigators slow down when closer than this to the end of their path.
+    pub arrival_distance: f32,
+    /// Navigators head to the waypoint after the next one once closer than this to it, if they
+    /// can see it, to cut the corner.
+    pub corner_radius: f32,
+    /// Velocity the navigator moved at on the last tick.
+    velocity: Vec2,
+}
+
+impl Default for Steering {
+    fn default() -> Self {
+        Self {
+            max_acceleration: 1.5,
+            max_turn_rate: PI,
+            arrival_distance: 1.0,
+            corner_radius: 1.0,
+            velocity: Vec2::ZERO,
+        }
+    }
+}
+
+impl Steering {
+    /// The velocity closest to `desired` that can be reached from the current one in `delta`
+    /// seconds.
+    fn limit(&self, desired: Vec2, delta: f32) -> Vec2 {
+        let speed = self.velocity.length();
+        let max_speed_change = self.max_acceleration * delta;
+        if speed <= f32::EPSILON {
+            // Standing still, navigators can face any direction.
+            return desired.clamp_length_max(max_speed_change);
+        }
+        let direction = self.velocity / speed;
+        if desired.length_squared() <= f32::EPSILON {
+            return direction * (speed - max_speed_change).max(0.0);
+        }
+        let angle = direction.angle_between(desired);
+        let max_turn = self.max_turn_rate * delta;
+        // Slow down to turn, down to a stop when the desired direction is behind.
+        let target_speed = desired.length() * angle.cos().max(0.0);
+        let new_speed = speed + (target_speed - speed).clamp(-max_speed_change, max_speed_change);
+        Vec2::from_angle(angle.clamp(-max_turn, max_turn)).rotate(direction) * new_speed
+    }
+
+    /// Speed to walk at toward the last waypoint of a path, `distance` away.
+    pub fn arrival_speed(&self, speed: f32, distance: f32) -> f32 {
+        if self.arrival_distance <= 0.0 {
+            return speed;
+        }
+        speed * (distance / self.arrival_distance).clamp(MIN_ARRIVAL_FACTOR, 1.0)
+    }
+
+    /// Distance within which a navigator reached a waypoint of its path.
+    pub fn reached_distance(&self) -> f32 {
+        self.arrival_distance.min(self.corner_radius).max(0.0) * REACHED_FACTOR
+    }
+}
+
+/// Limits the velocity navigators move at, and turns them to face where they walk.
+pub fn steer(
+    mut navigators: Query<(&mut Transform, &mut Steering, &mut Velocity)>,
+    navmeshes: Res<Assets<NavMesh>>,
+    navmesh: Query<&Handle<NavMesh>, Without<ClassNavMesh>>,
+    time: Res<Time>,
+) {
+    let delta = time.delta_seconds();
+    if delta <= 0.0 {
+        return;
+    }
+    let navmesh = navmesh
+        .get_single()
+        .ok()
+        .and_then(|handle| navmeshes.get(handle));
+    navigators
+        .par_iter_mut()
+        .for_each(|(mut transform, mut steering, mut velocity)| {
+            let mut limited = steering.limit(velocity.current, delta);
+            if velocity.current != velocity.preferred {
+                let target = transform.translation + Vec3::new(limited.x, 0.0, limited.y) * delta;
+                if navmesh.is_some_and(|navmesh| !navmesh.transformed_is_in_mesh(target)) {
+                    limited = steering.limit(velocity.preferred, delta);
+                }
+            }
+            steering.velocity = limited;
+            velocity.current = limited;
+            if limited.length_squared() > MIN_FACING_SPEED * MIN_FACING_SPEED {
+                transform.look_to(Vec3::new(limited.x, 0.0, limited.y), Vec3::Y);
+            }
+        });
+}